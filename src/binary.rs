@@ -0,0 +1,373 @@
+use crate::komsi::KomsiDateTime;
+#[cfg(feature = "std")]
+use crate::komsi::KomsiError;
+use crate::vehicle::VehicleState;
+
+/// Size of a record created by [`VehicleState::to_fixed_record`].
+pub const RECORD_SIZE: usize = 128;
+
+impl VehicleState {
+    /// Encodes the state as a fixed-size record for storage, e.g. in a binary time series file.
+    ///
+    /// This is not the KOMSI wire format. All multi-byte values are little-endian:
+    ///
+    /// | offset | size | field |
+    /// |-------:|-----:|-------|
+    /// | 0 | 1 | flags: bit 0 `ignition`, 1 `engine`, 2 `doors`, 3 `fixing_brake`, 4 `lights_warning`, 5 `lights_main`, 6 `lights_front_door`, 7 `lights_second_door` |
+    /// | 1 | 1 | flags: bit 0 `lights_third_door`, 1 `lights_fourth_door`, 2 `lights_stop_request`, 3 `lights_stop_brake`, 4 `lights_high_beam`, 5 `battery_light`, 6 `door_clearance`, 7 `ac_status` |
+    /// | 2 | 1 | `indicator` |
+    /// | 3 | 1 | `fuel` |
+    /// | 4 | 1 | `gear_selector` |
+    /// | 5 | 4 | `speed` |
+    /// | 9 | 4 | `maxspeed` |
+    /// | 13 | 2 | `datetime.year` |
+    /// | 15 | 5 | `datetime` month, day, hour, minute, second |
+    /// | 20 | 8 | `total_distance` |
+    /// | 28 | 8 | `total_distance_km` |
+    /// | 36 | 4 | `passengers` |
+    /// | 40 | 1 | flags: bit 0 `kneeling`, 1 `ramp` |
+    /// | 41 | 1 | `retarder` |
+    /// | 42 | 1 | `stop_request_door` |
+    /// | 43 | 4 | `water` |
+    /// | 47 | 4 | `rpm` |
+    /// | 51 | 4 | `pressure` |
+    /// | 55 | 4 | `next_stop` |
+    /// | 59 | 4 | `temperature` |
+    /// | 63 | 4 | `oil` |
+    /// | 67 | 1 | `simulator_type` |
+    /// | 68 | 60 | reserved, always 0 |
+    pub fn to_fixed_record(&self) -> [u8; RECORD_SIZE] {
+        let mut r = [0u8; RECORD_SIZE];
+        r[0] = flags(&[
+            self.ignition,
+            self.engine,
+            self.doors,
+            self.fixing_brake,
+            self.lights_warning,
+            self.lights_main,
+            self.lights_front_door,
+            self.lights_second_door,
+        ]);
+        r[1] = flags(&[
+            self.lights_third_door,
+            self.lights_fourth_door,
+            self.lights_stop_request,
+            self.lights_stop_brake,
+            self.lights_high_beam,
+            self.battery_light,
+            self.door_clearance,
+            self.ac_status,
+        ]);
+        r[2] = self.indicator;
+        r[3] = self.fuel;
+        r[4] = self.gear_selector;
+        r[5..9].copy_from_slice(&self.speed.to_le_bytes());
+        r[9..13].copy_from_slice(&self.maxspeed.to_le_bytes());
+        r[13..15].copy_from_slice(&self.datetime.year.to_le_bytes());
+        r[15..20].copy_from_slice(&[
+            self.datetime.month,
+            self.datetime.day,
+            self.datetime.hour,
+            self.datetime.min,
+            self.datetime.sec,
+        ]);
+        r[20..28].copy_from_slice(&self.total_distance.to_le_bytes());
+        r[28..36].copy_from_slice(&self.total_distance_km.to_le_bytes());
+        r[36..40].copy_from_slice(&self.passengers.to_le_bytes());
+        r[40] = flags(&[self.kneeling, self.ramp]);
+        r[41] = self.retarder;
+        r[42] = self.stop_request_door;
+        r[43..47].copy_from_slice(&self.water.to_le_bytes());
+        r[47..51].copy_from_slice(&self.rpm.to_le_bytes());
+        r[51..55].copy_from_slice(&self.pressure.to_le_bytes());
+        r[55..59].copy_from_slice(&self.next_stop.to_le_bytes());
+        r[59..63].copy_from_slice(&self.temperature.to_le_bytes());
+        r[63..67].copy_from_slice(&self.oil.to_le_bytes());
+        r[67] = self.simulator_type;
+        r
+    }
+
+    /// Decodes a record created by [`VehicleState::to_fixed_record`].
+    pub fn from_fixed_record(r: &[u8; RECORD_SIZE]) -> VehicleState {
+        let bit = |byte: usize, n: u8| r[byte] & (1 << n) != 0;
+        let u32_at = |i: usize| u32::from_le_bytes([r[i], r[i + 1], r[i + 2], r[i + 3]]);
+        let u64_at = |i: usize| {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&r[i..i + 8]);
+            u64::from_le_bytes(b)
+        };
+        VehicleState {
+            ignition: bit(0, 0),
+            engine: bit(0, 1),
+            doors: bit(0, 2),
+            fixing_brake: bit(0, 3),
+            lights_warning: bit(0, 4),
+            lights_main: bit(0, 5),
+            lights_front_door: bit(0, 6),
+            lights_second_door: bit(0, 7),
+            lights_third_door: bit(1, 0),
+            lights_fourth_door: bit(1, 1),
+            lights_stop_request: bit(1, 2),
+            lights_stop_brake: bit(1, 3),
+            lights_high_beam: bit(1, 4),
+            battery_light: bit(1, 5),
+            door_clearance: bit(1, 6),
+            ac_status: bit(1, 7),
+            indicator: r[2],
+            fuel: r[3],
+            gear_selector: r[4],
+            speed: u32_at(5),
+            maxspeed: u32_at(9),
+            datetime: KomsiDateTime {
+                year: u16::from_le_bytes([r[13], r[14]]),
+                month: r[15],
+                day: r[16],
+                hour: r[17],
+                min: r[18],
+                sec: r[19],
+            },
+            total_distance: u64_at(20),
+            total_distance_km: u64_at(28),
+            passengers: u32_at(36),
+            kneeling: bit(40, 0),
+            ramp: bit(40, 1),
+            retarder: r[41],
+            stop_request_door: r[42],
+            water: u32_at(43),
+            rpm: u32_at(47),
+            pressure: u32_at(51),
+            next_stop: u32_at(55),
+            temperature: u32_at(59),
+            oil: u32_at(63),
+            simulator_type: r[67],
+        }
+    }
+}
+
+/// Packs up to 8 booleans into a byte, the first one in bit 0.
+fn flags(bits: &[bool]) -> u8 {
+    bits.iter()
+        .enumerate()
+        .fold(0, |acc, (i, &b)| acc | ((b as u8) << i))
+}
+
+/// Trait for values with a fixed-size little-endian binary representation.
+#[cfg(feature = "std")]
+trait BinaryValue: Sized {
+    /// Appends the binary representation to the buffer.
+    fn write_bin(&self, buf: &mut Vec<u8>);
+    /// Reads the value from the start of `data` and returns it with the number of bytes read.
+    fn read_bin(data: &[u8]) -> Result<(Self, usize), KomsiError>;
+}
+
+#[cfg(feature = "std")]
+impl BinaryValue for bool {
+    fn write_bin(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+    fn read_bin(data: &[u8]) -> Result<(Self, usize), KomsiError> {
+        let b = *data.first().ok_or(KomsiError::InvalidValue)?;
+        Ok((b != 0, 1))
+    }
+}
+
+// Helper macro for integer implementations
+macro_rules! impl_binary_for_int {
+    ($($t:ty),*) => { $(
+        #[cfg(feature = "std")]
+        impl BinaryValue for $t {
+            fn write_bin(&self, buf: &mut Vec<u8>) { buf.extend_from_slice(&self.to_le_bytes()); }
+            fn read_bin(data: &[u8]) -> Result<(Self, usize), KomsiError> {
+                const N: usize = core::mem::size_of::<$t>();
+                let bytes = data.get(..N).ok_or(KomsiError::InvalidValue)?;
+                Ok((<$t>::from_le_bytes(bytes.try_into().unwrap()), N))
+            }
+        }
+    )* };
+}
+impl_binary_for_int!(u8, u16, u32, u64);
+
+#[cfg(feature = "std")]
+impl BinaryValue for KomsiDateTime {
+    fn write_bin(&self, buf: &mut Vec<u8>) {
+        self.year.write_bin(buf);
+        buf.extend_from_slice(&[self.month, self.day, self.hour, self.min, self.sec]);
+    }
+    fn read_bin(data: &[u8]) -> Result<(Self, usize), KomsiError> {
+        let (year, _) = u16::read_bin(data)?;
+        let b = data.get(2..7).ok_or(KomsiError::InvalidValue)?;
+        let dt = KomsiDateTime {
+            year,
+            month: b[0],
+            day: b[1],
+            hour: b[2],
+            min: b[3],
+            sec: b[4],
+        };
+        Ok((dt, 7))
+    }
+}
+
+// Generates the binary patch methods for the listed fields.
+// The position in the list is the bit in the patch mask, so new fields must only be appended.
+macro_rules! define_binary_patch {
+    ($($field:ident),* $(,)?) => {
+        #[cfg(feature = "std")]
+        impl VehicleState {
+            /// Creates a compact binary patch of all fields that differ between `self` and `new`.
+            ///
+            /// The patch starts with a little-endian `u64` bitmask of the changed fields, followed
+            /// by the new values of these fields in little-endian binary:
+            /// booleans and `u8` take 1 byte, `u32` 4 bytes, `u64` 8 bytes and the date/time
+            /// 7 bytes (year as `u16`, then month, day, hour, minute and second).
+            /// This is a storage/transmission format of its own and not the KOMSI wire format.
+            pub fn binary_patch(&self, new: &VehicleState) -> Vec<u8> {
+                let mut mask: u64 = 0;
+                let mut values: Vec<u8> = Vec::new();
+                let mut bit = 0;
+                $(
+                    if self.$field != new.$field {
+                        mask |= 1 << bit;
+                        new.$field.write_bin(&mut values);
+                    }
+                    bit += 1;
+                )*
+                let _ = bit;
+
+                let mut buffer = mask.to_le_bytes().to_vec();
+                buffer.append(&mut values);
+                buffer
+            }
+
+            /// Applies a patch created by [`VehicleState::binary_patch`] to this state.
+            ///
+            /// Returns `KomsiError::InvalidValue` if the patch is truncated, too long or
+            /// references unknown fields. The state is left unchanged on error.
+            pub fn apply_binary_patch(&mut self, patch: &[u8]) -> Result<(), KomsiError> {
+                let (mask, mut pos) = u64::read_bin(patch)?;
+                let mut patched = self.clone();
+                let mut bit = 0;
+                $(
+                    if mask & (1 << bit) != 0 {
+                        let (value, len) = BinaryValue::read_bin(&patch[pos..])?;
+                        patched.$field = value;
+                        pos += len;
+                    }
+                    bit += 1;
+                )*
+
+                if mask >> bit != 0 || pos != patch.len() {
+                    return Err(KomsiError::InvalidValue);
+                }
+                *self = patched;
+                Ok(())
+            }
+        }
+    };
+}
+
+define_binary_patch! {
+    ignition,
+    engine,
+    doors,
+    speed,
+    maxspeed,
+    fuel,
+    indicator,
+    fixing_brake,
+    lights_warning,
+    lights_main,
+    lights_front_door,
+    lights_second_door,
+    lights_third_door,
+    lights_fourth_door,
+    lights_stop_request,
+    lights_stop_brake,
+    lights_high_beam,
+    battery_light,
+    gear_selector,
+    door_clearance,
+    datetime,
+    total_distance,
+    total_distance_km,
+    passengers,
+    ac_status,
+    kneeling,
+    retarder,
+    ramp,
+    stop_request_door,
+    water,
+    rpm,
+    pressure,
+    next_stop,
+    temperature,
+    oil,
+    simulator_type,
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_patch_roundtrip() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.ignition = true;
+        new.speed = 50;
+        new.total_distance = 123456;
+        new.datetime.hour = 14;
+
+        let patch = old.binary_patch(&new);
+        // 8 byte mask + bool + u32 + datetime + u64
+        assert_eq!(patch.len(), 8 + 1 + 4 + 7 + 8);
+        assert_eq!(patch[..8], [0b1001, 0, 0x30, 0, 0, 0, 0, 0]);
+
+        let mut applied = old.clone();
+        applied.apply_binary_patch(&patch).unwrap();
+        assert_eq!(applied, new);
+    }
+
+    #[test]
+    fn test_fixed_record_roundtrip() {
+        let mut state = VehicleState::new();
+        state.ignition = true;
+        state.lights_third_door = true;
+        state.indicator = 2;
+        state.speed = 0x0102;
+        state.total_distance = 123456;
+        state.datetime.year = 2026;
+        state.passengers = 12;
+
+        let record = state.to_fixed_record();
+        assert_eq!(record.len(), RECORD_SIZE);
+        assert_eq!(record[0], 0b0000_0001);
+        assert_eq!(record[1], 0b0000_0001);
+        assert_eq!(record[2], 2);
+        assert_eq!(record[5..9], [0x02, 0x01, 0, 0]);
+        assert_eq!(record[36], 12);
+        assert!(record[68..].iter().all(|&b| b == 0));
+
+        assert_eq!(VehicleState::from_fixed_record(&record), state);
+    }
+
+    #[test]
+    fn test_binary_patch_no_change() {
+        let state = VehicleState::new();
+        assert_eq!(state.binary_patch(&state), [0u8; 8]);
+    }
+
+    #[test]
+    fn test_apply_binary_patch_invalid() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.speed = 50;
+        let patch = old.binary_patch(&new);
+
+        let mut state = VehicleState::new();
+        assert!(state.apply_binary_patch(&patch[..patch.len() - 1]).is_err()); // truncated
+        let unknown_field = [0, 0, 0, 0, 0, 0, 0, 0x80];
+        assert!(state.apply_binary_patch(&unknown_field).is_err());
+        assert_eq!(state, old);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::komsi::{KomsiDateTime, KomsiError};
+use crate::vehicle::VehicleState;
+
+/// Trait for values with a text representation in a CSV column.
+trait CsvValue: Sized {
+    /// Returns the text of the column.
+    fn to_csv(&self) -> String;
+    /// Parses the value from the text of a column.
+    fn from_csv(s: &str) -> Result<Self, KomsiError>;
+}
+
+impl CsvValue for bool {
+    fn to_csv(&self) -> String {
+        (*self as u8).to_string()
+    }
+    fn from_csv(s: &str) -> Result<Self, KomsiError> {
+        match s {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(KomsiError::InvalidValue),
+        }
+    }
+}
+
+// Helper macro for integer implementations
+macro_rules! impl_csv_for_int {
+    ($($t:ty),*) => { $(
+        impl CsvValue for $t {
+            fn to_csv(&self) -> String { self.to_string() }
+            fn from_csv(s: &str) -> Result<Self, KomsiError> {
+                s.parse().map_err(|_| KomsiError::InvalidValue)
+            }
+        }
+    )* };
+}
+impl_csv_for_int!(u8, u32, u64);
+
+impl CsvValue for KomsiDateTime {
+    fn to_csv(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.min, self.sec
+        )
+    }
+    fn from_csv(s: &str) -> Result<Self, KomsiError> {
+        KomsiDateTime::from_iso(s)
+    }
+}
+
+// Generates the CSV methods for the listed fields, in column order.
+macro_rules! define_csv_columns {
+    ($($field:ident),* $(,)?) => {
+        impl VehicleState {
+            /// Returns the CSV header line matching [`VehicleState::to_csv_row`], without line break.
+            pub fn csv_header() -> String {
+                [$(stringify!($field)),*].join(",")
+            }
+
+            /// Returns all fields as a single CSV row, without line break.
+            ///
+            /// The columns are the fields in declaration order (`ignition`, `engine`, `doors`,
+            /// `speed`, ...), as named by [`VehicleState::csv_header`]. New fields are appended.
+            /// Booleans are written as 0 or 1 and the date/time as `YYYY-MM-DDTHH:MM:SS`.
+            pub fn to_csv_row(&self) -> String {
+                [$(self.$field.to_csv()),*].join(",")
+            }
+
+            /// Parses a row created by [`VehicleState::to_csv_row`].
+            ///
+            /// Whitespace around the columns is ignored. Returns `KomsiError::InvalidValue` if the
+            /// number of columns does not match or a column can not be parsed.
+            pub fn from_csv_row(row: &str) -> Result<VehicleState, KomsiError> {
+                let mut columns = row.trim_end_matches(['\r', '\n']).split(',').map(str::trim);
+                let state = VehicleState {
+                    $($field: CsvValue::from_csv(columns.next().ok_or(KomsiError::InvalidValue)?)?),*
+                };
+                if columns.next().is_some() {
+                    return Err(KomsiError::InvalidValue);
+                }
+                Ok(state)
+            }
+        }
+    };
+}
+
+define_csv_columns! {
+    ignition,
+    engine,
+    doors,
+    speed,
+    maxspeed,
+    fuel,
+    indicator,
+    fixing_brake,
+    lights_warning,
+    lights_main,
+    lights_front_door,
+    lights_second_door,
+    lights_third_door,
+    lights_fourth_door,
+    lights_stop_request,
+    lights_stop_brake,
+    lights_high_beam,
+    battery_light,
+    gear_selector,
+    door_clearance,
+    datetime,
+    total_distance,
+    total_distance_km,
+    passengers,
+    ac_status,
+    kneeling,
+    retarder,
+    ramp,
+    stop_request_door,
+    water,
+    rpm,
+    pressure,
+    next_stop,
+    temperature,
+    oil,
+    simulator_type,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_roundtrip() {
+        let mut state = VehicleState::new();
+        state.ignition = true;
+        state.speed = 50;
+        state.datetime.hour = 14;
+        state.passengers = 7;
+
+        let row = state.to_csv_row();
+        assert!(row.starts_with("1,0,0,50,0,0,0,"));
+        assert!(row.contains(",2000-01-01T14:00:00,0,0,7"));
+        assert_eq!(
+            VehicleState::csv_header().split(',').count(),
+            row.split(',').count()
+        );
+        assert!(VehicleState::csv_header().starts_with("ignition,engine,doors,speed,"));
+
+        assert_eq!(VehicleState::from_csv_row(&row), Ok(state.clone()));
+        // spreadsheet exports may contain spaces and a line break
+        let spaced = row.replace(',', ", ") + "\r\n";
+        assert_eq!(VehicleState::from_csv_row(&spaced), Ok(state));
+    }
+
+    #[test]
+    fn test_csv_invalid() {
+        let row = VehicleState::new().to_csv_row();
+        let too_long = format!("{row},1");
+        assert!(VehicleState::from_csv_row(&too_long).is_err());
+        let too_short = &row[..row.rfind(',').unwrap()];
+        assert!(VehicleState::from_csv_row(too_short).is_err());
+        let invalid_bool = row.replacen('0', "2", 1);
+        assert!(VehicleState::from_csv_row(&invalid_bool).is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::komsi::{
+    KomsiCommand, KomsiCommandKind, KomsiError, LineEnding, READY_RESPONSE, TEXT_COMMAND,
+};
+use crate::vehicle::{VehicleLogger, VehicleState};
+
+/// The command bytes the protocol reserves for custom commands (`Q` to `Z`).
+pub const RESERVED_CODES: std::ops::RangeInclusive<u8> = 81..=90;
+
+/// Linear scaling of a command value.
+///
+/// The transmitted value is `value * scale + offset`, rounded to the nearest integer
+/// and clamped to the range of the command's value type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scaling {
+    /// Factor the value is multiplied with.
+    pub scale: f64,
+    /// Offset added after scaling.
+    pub offset: f64,
+}
+
+impl Scaling {
+    /// Applies the scaling to a value.
+    pub fn apply(&self, value: u64) -> u64 {
+        // the float to int cast saturates, negative results become 0
+        (value as f64 * self.scale + self.offset).round() as u64
+    }
+}
+
+/// Byte order of binary encoded values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryEndian {
+    /// Most significant byte first.
+    Big,
+    /// Least significant byte first.
+    Little,
+}
+
+/// A custom transformation of a command value.
+pub type Transform = Arc<dyn Fn(u64) -> u64 + Send + Sync>;
+
+/// Board-specific adjustments applied when building KOMSI commands.
+///
+/// A default dialect leaves all commands unchanged.
+#[derive(Clone, Default)]
+pub struct Dialect {
+    /// Per-command value scaling.
+    scaling: BTreeMap<KomsiCommandKind, Scaling>,
+    /// Per-command custom value transformations, applied after scaling.
+    transforms: BTreeMap<KomsiCommandKind, Transform>,
+    /// Commands whose value is sent as 4 binary bytes instead of ASCII digits.
+    binary: BTreeMap<KomsiCommandKind, BinaryEndian>,
+    /// Commands sent with a different command byte than the standard one.
+    codes: BTreeMap<KomsiCommandKind, u8>,
+    /// The line terminator of the frames.
+    line_ending: LineEnding,
+}
+
+impl fmt::Debug for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dialect")
+            .field("scaling", &self.scaling)
+            .field("transforms", &self.transforms.keys().collect::<Vec<_>>())
+            .field("binary", &self.binary)
+            .field("codes", &self.codes)
+            .field("line_ending", &self.line_ending)
+            .finish()
+    }
+}
+
+impl Dialect {
+    /// Creates a new dialect without any adjustments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a dialect with command byte overrides from a config text.
+    ///
+    /// Every line assigns a command byte to a command kind, e.g. `speed=0x79` or `fuel=120`.
+    /// Names are matched case-insensitively and ignoring underscores, so `max_speed` is
+    /// `MaxSpeed`. Empty lines and lines starting with `#` are skipped.
+    /// Returns `KomsiError::UnknownName` for unknown names, `KomsiError::InvalidValue` for
+    /// malformed lines and `KomsiError::InvalidCommand` if two command kinds end up with the
+    /// same byte or a command kind is sent with a reserved byte (see [`Dialect::validate`]).
+    pub fn from_config_str(s: &str) -> Result<Dialect, KomsiError> {
+        let mut dialect = Dialect::new();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, code) = line.split_once('=').ok_or(KomsiError::InvalidValue)?;
+            let kind = kind_by_name(name.trim()).ok_or(KomsiError::UnknownName)?;
+            let code = code.trim();
+            let code = match code.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => code.parse(),
+            }
+            .map_err(|_| KomsiError::InvalidValue)?;
+            dialect.set_code(kind, code)?;
+        }
+        dialect.validate()?;
+        Ok(dialect)
+    }
+
+    /// Sends the given command kind with a different command byte.
+    ///
+    /// Returns `KomsiError::InvalidCommand` if another command kind is already sent with
+    /// this byte.
+    pub fn set_code(&mut self, kind: KomsiCommandKind, code: u8) -> Result<(), KomsiError> {
+        if KomsiCommandKind::ALL
+            .iter()
+            .any(|&other| other != kind && self.code(other) == code)
+        {
+            return Err(KomsiError::InvalidCommand(code as char));
+        }
+        self.codes.insert(kind, code);
+        Ok(())
+    }
+
+    /// Returns the command byte the given command kind is sent with.
+    pub fn code(&self, kind: KomsiCommandKind) -> u8 {
+        self.codes.get(&kind).copied().unwrap_or(kind as u8)
+    }
+
+    /// Checks that every command kind is sent with a unique byte that does not collide with
+    /// a byte the protocol reserves for other purposes.
+    ///
+    /// Reserved are the digits (they are read as part of the value), the line ending bytes
+    /// `\n` and `\r` (see [`LineEnding`]), the text command byte and
+    /// the [`READY_RESPONSE`] byte. Returns `KomsiError::InvalidCommand` with the first
+    /// offending byte.
+    pub fn validate(&self) -> Result<(), KomsiError> {
+        let mut used: BTreeMap<u8, KomsiCommandKind> = BTreeMap::new();
+        for &kind in KomsiCommandKind::ALL {
+            let code = self.code(kind);
+            let reserved = code.is_ascii_digit()
+                || code == b'\n'
+                || code == b'\r'
+                || code == TEXT_COMMAND
+                || code == READY_RESPONSE;
+            if reserved || used.insert(code, kind).is_some() {
+                return Err(KomsiError::InvalidCommand(code as char));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the [`RESERVED_CODES`] no command kind is sent with, in ascending order.
+    ///
+    /// The text command byte is never free. Use the result to pick a byte for
+    /// [`Dialect::set_code`].
+    pub fn free_reserved_codes(&self) -> Vec<u8> {
+        RESERVED_CODES
+            .filter(|&code| code != TEXT_COMMAND)
+            .filter(|&code| {
+                KomsiCommandKind::ALL
+                    .iter()
+                    .all(|&kind| self.code(kind) != code)
+            })
+            .collect()
+    }
+
+    /// Sets the line terminator of the frames built by this dialect, [`LineEnding::Lf`]
+    /// by default.
+    pub fn set_line_ending(&mut self, ending: LineEnding) {
+        self.line_ending = ending;
+    }
+
+    /// Sets the scaling applied to the value of the given command kind.
+    pub fn set_scaling(&mut self, kind: KomsiCommandKind, scale: f64, offset: f64) {
+        self.scaling.insert(kind, Scaling { scale, offset });
+    }
+
+    /// Returns the scaling of the given command kind, if any.
+    pub fn scaling(&self, kind: KomsiCommandKind) -> Option<&Scaling> {
+        self.scaling.get(&kind)
+    }
+
+    /// Sets a custom transformation of the value of the given command kind,
+    /// e.g. a gamma curve that can not be expressed by a linear scaling.
+    ///
+    /// The transformation is applied after the scaling; the result saturates at the bounds
+    /// of the command's value type.
+    pub fn set_transform<F>(&mut self, kind: KomsiCommandKind, f: F)
+    where
+        F: Fn(u64) -> u64 + Send + Sync + 'static,
+    {
+        self.transforms.insert(kind, Arc::new(f));
+    }
+
+    /// Sends the value of the given `u32` command kind as 4 binary bytes in the given byte order
+    /// instead of ASCII digits, or as ASCII digits again with `None`.
+    ///
+    /// Binary values may contain any byte, so the board has to know the fixed value length
+    /// of these commands. Returns `KomsiError::InvalidCommand` for command kinds whose value
+    /// is not a `u32`.
+    pub fn set_binary_encoding(
+        &mut self,
+        kind: KomsiCommandKind,
+        endian: Option<BinaryEndian>,
+    ) -> Result<(), KomsiError> {
+        if kind.value_type() != "u32" {
+            return Err(KomsiError::InvalidCommand(kind as u8 as char));
+        }
+        match endian {
+            Some(e) => self.binary.insert(kind, e),
+            None => self.binary.remove(&kind),
+        };
+        Ok(())
+    }
+
+    /// Returns the command with all adjustments of this dialect applied.
+    pub fn apply(&self, cmd: &KomsiCommand) -> KomsiCommand {
+        let mut cmd = *cmd;
+        if let Some(s) = self.scaling.get(&cmd.kind()) {
+            cmd = cmd.map_value(&|v| s.apply(v));
+        }
+        if let Some(t) = self.transforms.get(&cmd.kind()) {
+            cmd = cmd.map_value(t.as_ref());
+        }
+        cmd
+    }
+
+    /// Creates a KOMSI command buffer from the command using this dialect.
+    pub fn build(&self, cmd: &KomsiCommand) -> Vec<u8> {
+        self.encode(&self.apply(cmd))
+    }
+
+    /// Creates a KOMSI command buffer from a raw value that is scaled and transformed first,
+    /// e.g. ADC counts 0-1023 for `Fuel` that do not fit its `u8` before scaling.
+    ///
+    /// The result saturates at the bounds of the command's value type.
+    /// Returns `KomsiError::InvalidDateTime` for date/time commands.
+    pub fn build_scaled(&self, kind: KomsiCommandKind, raw: u64) -> Result<Vec<u8>, KomsiError> {
+        let mut value = self.scaling.get(&kind).map_or(raw, |s| s.apply(raw));
+        if let Some(t) = self.transforms.get(&kind) {
+            value = t(value);
+        }
+        let cmd = match KomsiCommand::from_value(kind, value) {
+            Ok(cmd) => cmd,
+            Err(_) => KomsiCommand::from_value(kind, 0)?.map_value(&|_| value),
+        };
+        Ok(self.encode(&cmd))
+    }
+
+    /// Serializes an already adjusted command with the codes and encodings of this dialect.
+    fn encode(&self, cmd: &KomsiCommand) -> Vec<u8> {
+        let cmd = *cmd;
+        let Some(endian) = self.binary.get(&cmd.kind()) else {
+            let mut buffer = cmd.build();
+            buffer[0] = self.code(cmd.kind());
+            return buffer;
+        };
+
+        // only u32 commands can be binary encoded, so the value always fits
+        let value = cmd.value() as u32;
+        let bytes = match endian {
+            BinaryEndian::Big => value.to_be_bytes(),
+            BinaryEndian::Little => value.to_le_bytes(),
+        };
+        let mut buffer = vec![self.code(cmd.kind())];
+        buffer.extend_from_slice(&bytes);
+        buffer
+    }
+
+    /// Serializes the commands using this dialect into a single buffer terminated by the
+    /// line ending of this dialect.
+    ///
+    /// Returns an empty buffer if there are no commands.
+    pub fn build_frame(&self, commands: &[KomsiCommand]) -> Vec<u8> {
+        let mut buffer: Vec<u8> = commands.iter().flat_map(|cmd| self.build(cmd)).collect();
+        if !buffer.is_empty() {
+            buffer.extend_from_slice(self.line_ending.as_bytes());
+        }
+        buffer
+    }
+
+    /// Compares two states like [`VehicleState::compare`] and builds the result using this dialect.
+    pub fn compare(
+        &self,
+        old: &VehicleState,
+        new: &VehicleState,
+        force: bool,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<u8> {
+        self.build_frame(&old.compare_commands(new, force, logger))
+    }
+}
+
+/// Returns the command kind with the given name, ignoring case and underscores.
+fn kind_by_name(name: &str) -> Option<KomsiCommandKind> {
+    name.replace('_', "").parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scaling() {
+        let mut dialect = Dialect::new();
+        // raw ADC counts 0-1023 to percent
+        dialect.set_scaling(KomsiCommandKind::Fuel, 100.0 / 1023.0, 0.0);
+
+        assert_eq!(
+            dialect.build_scaled(KomsiCommandKind::Fuel, 1023),
+            Ok(b"x100".to_vec())
+        );
+        assert_eq!(
+            dialect.build_scaled(KomsiCommandKind::Fuel, 512),
+            Ok(b"x50".to_vec())
+        );
+        assert_eq!(
+            dialect.build_scaled(KomsiCommandKind::Fuel, 0),
+            Ok(b"x0".to_vec())
+        );
+        assert_eq!(dialect.build(&KomsiCommand::Fuel(255)), b"x25");
+        assert_eq!(dialect.build(&KomsiCommand::Speed(50)), b"y50"); // not scaled
+
+        let mut old = VehicleState::new();
+        old.fuel = 200;
+        let mut new = old.clone();
+        new.fuel = 255;
+        assert_eq!(dialect.compare(&old, &new, false, None), b"x25\n");
+    }
+
+    #[test]
+    fn test_scaling_offset_clamps() {
+        let mut dialect = Dialect::new();
+        dialect.set_scaling(KomsiCommandKind::Speed, 1.0, -10.0);
+
+        assert_eq!(dialect.build(&KomsiCommand::Speed(50)), b"y40");
+        assert_eq!(dialect.build(&KomsiCommand::Speed(5)), b"y0");
+
+        // raw values beyond the value type saturate after scaling
+        dialect.set_scaling(KomsiCommandKind::Fuel, 0.5, 0.0);
+        assert_eq!(
+            dialect.build_scaled(KomsiCommandKind::Fuel, 1023),
+            Ok(b"x255".to_vec())
+        );
+        assert_eq!(
+            dialect.build_scaled(KomsiCommandKind::DateTime, 1),
+            Err(KomsiError::InvalidDateTime)
+        );
+    }
+
+    #[test]
+    fn test_transform() {
+        let mut dialect = Dialect::new();
+        dialect.set_transform(KomsiCommandKind::Fuel, |v| v * v / 100);
+        assert_eq!(dialect.build(&KomsiCommand::Fuel(50)), b"x25");
+
+        // transforms run after scaling
+        dialect.set_scaling(KomsiCommandKind::Fuel, 2.0, 0.0);
+        assert_eq!(dialect.build(&KomsiCommand::Fuel(50)), b"x100");
+
+        let cloned = dialect.clone();
+        assert_eq!(cloned.build(&KomsiCommand::Fuel(10)), b"x4");
+    }
+
+    #[test]
+    fn test_binary_encoding() {
+        let mut dialect = Dialect::new();
+        dialect
+            .set_binary_encoding(KomsiCommandKind::Speed, Some(BinaryEndian::Big))
+            .unwrap();
+        dialect
+            .set_binary_encoding(KomsiCommandKind::MaxSpeed, Some(BinaryEndian::Little))
+            .unwrap();
+
+        assert_eq!(dialect.build(&KomsiCommand::Speed(258)), [b'y', 0, 0, 1, 2]);
+        assert_eq!(
+            dialect.build(&KomsiCommand::MaxSpeed(258)),
+            [b's', 2, 1, 0, 0]
+        );
+        assert_eq!(dialect.build(&KomsiCommand::Fuel(5)), b"x5");
+
+        dialect
+            .set_binary_encoding(KomsiCommandKind::Speed, None)
+            .unwrap();
+        assert_eq!(dialect.build(&KomsiCommand::Speed(258)), b"y258");
+
+        let result = dialect.set_binary_encoding(KomsiCommandKind::Fuel, Some(BinaryEndian::Big));
+        assert_eq!(result, Err(KomsiError::InvalidCommand('x')));
+    }
+
+    #[test]
+    fn test_build_frame_binary() {
+        let mut dialect = Dialect::new();
+        dialect
+            .set_binary_encoding(KomsiCommandKind::Speed, Some(BinaryEndian::Big))
+            .unwrap();
+        let frame = dialect.build_frame(&[KomsiCommand::Speed(258), KomsiCommand::Fuel(5)]);
+        assert_eq!(frame, [b'y', 0, 0, 1, 2, b'x', b'5', b'\n']);
+        assert!(dialect.build_frame(&[]).is_empty());
+
+        dialect.set_line_ending(LineEnding::CrLf);
+        let frame = dialect.build_frame(&[KomsiCommand::Fuel(5)]);
+        assert_eq!(frame, b"x5\r\n");
+    }
+
+    #[test]
+    fn test_from_config_str() {
+        let config = "# board rev. B\nspeed=0x21\n\n max_speed = 36\n";
+        let dialect = Dialect::from_config_str(config).unwrap();
+        assert_eq!(dialect.code(KomsiCommandKind::Speed), b'!');
+        assert_eq!(dialect.code(KomsiCommandKind::MaxSpeed), b'$');
+        assert_eq!(dialect.code(KomsiCommandKind::Fuel), b'x');
+        assert_eq!(dialect.build(&KomsiCommand::Speed(50)), b"!50");
+
+        let frame = dialect.build_frame(&[KomsiCommand::MaxSpeed(60), KomsiCommand::Fuel(5)]);
+        assert_eq!(frame, b"$60x5\n");
+    }
+
+    #[test]
+    fn test_from_config_str_invalid() {
+        let result = Dialect::from_config_str("speed=120");
+        assert_eq!(result.unwrap_err(), KomsiError::InvalidCommand('x')); // fuel
+        let result = Dialect::from_config_str("speed=0x21\nfuel=0x21");
+        assert_eq!(result.unwrap_err(), KomsiError::InvalidCommand('!'));
+        let result = Dialect::from_config_str("warp=0x21");
+        assert_eq!(result.unwrap_err(), KomsiError::UnknownName);
+        let result = Dialect::from_config_str("speed");
+        assert_eq!(result.unwrap_err(), KomsiError::InvalidValue);
+        let result = Dialect::from_config_str("speed=0x100");
+        assert_eq!(result.unwrap_err(), KomsiError::InvalidValue);
+        // reserved bytes: the line feed and a digit
+        let result = Dialect::from_config_str("speed=0x0A");
+        assert_eq!(result.unwrap_err(), KomsiError::InvalidCommand('\n'));
+        let result = Dialect::from_config_str("speed=0x31");
+        assert_eq!(result.unwrap_err(), KomsiError::InvalidCommand('1'));
+    }
+
+    #[test]
+    fn test_free_reserved_codes() {
+        let mut dialect = Dialect::new();
+        assert_eq!(dialect.free_reserved_codes(), b"RW");
+
+        // moving a command off the reserved range frees its code
+        dialect.set_code(KomsiCommandKind::Kneeling, 0x21).unwrap();
+        dialect
+            .set_code(KomsiCommandKind::FourthDoor, 0x24)
+            .unwrap();
+        assert_eq!(dialect.free_reserved_codes(), b"QRTW");
+
+        let code = dialect.free_reserved_codes()[0];
+        dialect.set_code(KomsiCommandKind::Speed, code).unwrap();
+        assert_eq!(dialect.free_reserved_codes(), b"RTW");
+        assert_eq!(dialect.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(Dialect::new().validate(), Ok(()));
+        assert_eq!(
+            Dialect::from_config_str("speed=0x21").unwrap().validate(),
+            Ok(())
+        );
+
+        for code in [0x35, 10, 13, 0x56, 0x06] {
+            let mut dialect = Dialect::new();
+            dialect.set_code(KomsiCommandKind::Speed, code).unwrap();
+            assert!(dialect.validate().is_err(), "{code}");
+            let config = format!("speed={code}");
+            assert!(Dialect::from_config_str(&config).is_err(), "{code}");
+        }
+    }
+}
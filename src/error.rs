@@ -0,0 +1,108 @@
+use core::fmt;
+
+use crate::komsi::KomsiCommandKind;
+
+/// Represents the different errors that can occur in the KOMSI protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KomsiError {
+    /// An invalid command character was encountered.
+    InvalidCommand(char),
+    /// An invalid value was encountered.
+    InvalidValue,
+    /// An invalid date/time format was encountered.
+    InvalidDateTime,
+    /// A received byte is not a command byte.
+    UnknownCommand(u8),
+    /// No command kind has the given name.
+    UnknownName,
+    /// A command was expected, but the input is empty.
+    EmptyCommand,
+    /// A state did not survive the roundtrip through the wire format.
+    RoundtripMismatch,
+    /// A received value contains a byte that is not an ASCII digit.
+    InvalidDigit(u8),
+    /// A received command of the given kind has no value.
+    EmptyValue(KomsiCommandKind),
+    /// A received value does not fit the value type of its command.
+    ValueOverflow,
+    /// A field of a `VehicleState` has a value outside of its range, with the field name and
+    /// the value.
+    InvalidField(&'static str, u64),
+    /// The integrity check of a received line (see `Framing`) is missing or does not match.
+    ChecksumMismatch,
+    /// The output buffer is too small for the serialized command.
+    BufferTooSmall,
+    /// The value of a command in a list does not fit its kind, with the index of the command
+    /// in the list and its kind.
+    InvalidValueAt(usize, KomsiCommandKind),
+}
+
+impl fmt::Display for KomsiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCommand(c) => write!(f, "invalid command byte 0x{:02x}", *c as u32),
+            Self::InvalidValue => write!(f, "invalid value"),
+            Self::InvalidDateTime => write!(f, "invalid date/time"),
+            Self::UnknownCommand(b) => write!(f, "unknown command byte 0x{:02x}", b),
+            Self::UnknownName => write!(f, "unknown command name"),
+            Self::EmptyCommand => write!(f, "empty command"),
+            Self::RoundtripMismatch => {
+                write!(f, "state does not survive the wire format roundtrip")
+            }
+            Self::InvalidDigit(b) => write!(f, "invalid digit 0x{:02x} in value", b),
+            Self::EmptyValue(kind) => write!(f, "missing value of command {}", kind.name()),
+            Self::ValueOverflow => write!(f, "value out of range"),
+            Self::InvalidField(name, value) => write!(f, "invalid {name} value {value}"),
+            Self::ChecksumMismatch => write!(f, "integrity check of the line failed"),
+            Self::BufferTooSmall => write!(f, "output buffer too small"),
+            Self::InvalidValueAt(index, kind) => {
+                write!(
+                    f,
+                    "invalid value of command {} at index {index}",
+                    kind.name()
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for KomsiError {}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            KomsiError::InvalidCommand('#').to_string(),
+            "invalid command byte 0x23"
+        );
+        assert_eq!(
+            KomsiError::UnknownCommand(b'#').to_string(),
+            "unknown command byte 0x23"
+        );
+        assert_eq!(
+            KomsiError::EmptyValue(KomsiCommandKind::Speed).to_string(),
+            "missing value of command Speed"
+        );
+
+        assert_eq!(
+            KomsiError::InvalidField("indicator", 7).to_string(),
+            "invalid indicator value 7"
+        );
+        assert_eq!(
+            KomsiError::BufferTooSmall.to_string(),
+            "output buffer too small"
+        );
+        assert_eq!(
+            KomsiError::InvalidValueAt(3, KomsiCommandKind::Fuel).to_string(),
+            "invalid value of command Fuel at index 3"
+        );
+
+        let err: Box<dyn std::error::Error> = Box::new(KomsiError::ValueOverflow);
+        assert_eq!(err.to_string(), "value out of range");
+    }
+}
@@ -1,21 +1,13 @@
-/// Represents the different errors that can occur in the KOMSI protocol.
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub enum KomsiError {
-    /// An invalid command character was encountered.
-    InvalidCommand(char),
-    /// An invalid value was encountered.
-    InvalidValue,
-    /// An invalid date/time format was encountered.
-    InvalidDateTime,
-    /// The command is unknown.
-    UnknownCommand,
-}
+pub use crate::error::KomsiError;
+
+#[cfg(feature = "alloc")]
+use alloc::{format, vec, vec::Vec};
 
 /// Represents a date and time in the KOMSI protocol.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 // Wenn das Feature "defmt" aktiv ist, füge das Format-Derive hinzu
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct KomsiDateTime {
     /// Year (e.g., 2024)
     pub year: u16,
@@ -31,6 +23,33 @@ pub struct KomsiDateTime {
     pub sec: u8,
 }
 
+/// Logical groups of KOMSI commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandGroup {
+    /// Driving related commands (ignition, engine, speed, ...).
+    Driving,
+    /// Cosmetic lights (main lights, high beam, stop request).
+    Lights,
+    /// Warning lamps (hazard lights, battery light).
+    Warnings,
+    /// Door commands.
+    Doors,
+    /// Brake commands.
+    Brakes,
+    /// Engine sensor gauges (rpm, pressure, temperatures, oil).
+    Sensors,
+    /// Protocol and system commands.
+    System,
+}
+
+impl CommandGroup {
+    /// Returns true if commands of this group are safety-critical (brakes, doors and warnings).
+    pub fn is_safety_critical(&self) -> bool {
+        matches!(self, Self::Brakes | Self::Doors | Self::Warnings)
+    }
+}
+
 impl KomsiDateTime {
     /// Parses a date time string in the format "YYYY-MM-DDTHH:MM:SS" into a KomsiDateTime.
     pub fn from_iso(s: &str) -> Result<Self, KomsiError> {
@@ -44,8 +63,8 @@ impl KomsiDateTime {
 
         let p16 = |start: usize, end: usize| -> Result<u16, KomsiError> {
             let mut n = 0u16;
-            for i in start..end {
-                let digit = b[i].checked_sub(b'0').ok_or(KomsiError::InvalidValue)?;
+            for &c in &b[start..end] {
+                let digit = c.checked_sub(b'0').ok_or(KomsiError::InvalidValue)?;
                 if digit > 9 {
                     return Err(KomsiError::InvalidValue);
                 }
@@ -59,8 +78,8 @@ impl KomsiDateTime {
 
         let p8 = |start: usize, end: usize| -> Result<u8, KomsiError> {
             let mut n = 0u8;
-            for i in start..end {
-                let digit = b[i].checked_sub(b'0').ok_or(KomsiError::InvalidValue)?;
+            for &c in &b[start..end] {
+                let digit = c.checked_sub(b'0').ok_or(KomsiError::InvalidValue)?;
                 if digit > 9 {
                     return Err(KomsiError::InvalidValue);
                 }
@@ -97,6 +116,19 @@ pub trait ToRaw {
     fn to_raw(&self, buf: &mut [u8]) -> usize;
 }
 
+/// Trait for transforming the numeric value of a type.
+pub trait MapRaw: Sized {
+    /// Applies `f` to the numeric value, saturating at the bounds of the type.
+    /// Types without a numeric value are returned unchanged.
+    fn map_raw(self, f: &dyn Fn(u64) -> u64) -> Self;
+}
+
+/// Trait for reading the numeric value of a type.
+pub trait RawValue {
+    /// Returns the value as transmitted, as a number.
+    fn raw_value(&self) -> u64;
+}
+
 // --- IMPLEMENTATIONS OF HELPER TRAITS ---
 
 impl FromRaw for bool {
@@ -108,7 +140,7 @@ impl FromRaw for bool {
 impl ToRaw for bool {
     /// Serializes a boolean to '1' or '0'.
     fn to_raw(&self, buf: &mut [u8]) -> usize {
-        if buf.len() >= 1 {
+        if !buf.is_empty() {
             buf[0] = if *self { b'1' } else { b'0' };
             1
         } else {
@@ -117,15 +149,37 @@ impl ToRaw for bool {
     }
 }
 
+impl RawValue for bool {
+    /// Returns 1 for true and 0 for false.
+    fn raw_value(&self) -> u64 {
+        *self as u64
+    }
+}
+
+impl MapRaw for bool {
+    /// Booleans are not transformed.
+    fn map_raw(self, _: &dyn Fn(u64) -> u64) -> Self {
+        self
+    }
+}
+
 // Helper macro for integer implementations
 macro_rules! impl_raw_for_int {
     ($($t:ty),*) => { $(
         impl FromRaw for $t {
-            fn from_raw(v: u64, _: &[u8]) -> Result<Self, KomsiError> { Ok(v as $t) }
+            fn from_raw(v: u64, _: &[u8]) -> Result<Self, KomsiError> {
+                <$t>::try_from(v).map_err(|_| KomsiError::InvalidValue)
+            }
         }
         impl ToRaw for $t {
             fn to_raw(&self, buf: &mut [u8]) -> usize { write_u64_to_buf(*self as u64, buf) }
         }
+        impl RawValue for $t {
+            fn raw_value(&self) -> u64 { *self as u64 }
+        }
+        impl MapRaw for $t {
+            fn map_raw(self, f: &dyn Fn(u64) -> u64) -> Self { f(self as u64).min(<$t>::MAX as u64) as $t }
+        }
     )* };
 }
 impl_raw_for_int!(u8, u16, u32, u64);
@@ -152,9 +206,25 @@ impl ToRaw for KomsiDateTime {
     }
 }
 
+impl RawValue for KomsiDateTime {
+    /// Returns the date and time as the number YYYYMMDDHHMMSS.
+    fn raw_value(&self) -> u64 {
+        let date = self.year as u64 * 10_000 + self.month as u64 * 100 + self.day as u64;
+        let time = self.hour as u64 * 10_000 + self.min as u64 * 100 + self.sec as u64;
+        date * 1_000_000 + time
+    }
+}
+
+impl MapRaw for KomsiDateTime {
+    /// Date and time values are not transformed.
+    fn map_raw(self, _: &dyn Fn(u64) -> u64) -> Self {
+        self
+    }
+}
+
 // --- THE MAIN MACRO ---
 macro_rules! define_komsi_commands {
-    ($($name:ident = $char:expr => $type:ty),* $(,)?) => {
+    ($($name:ident = $char:expr => $type:ty [$group:ident]),* $(,)?) => {
         /// Represents a KOMSI command.
         #[derive(Debug, Clone, Copy, PartialEq)]
         #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -162,6 +232,56 @@ macro_rules! define_komsi_commands {
             $($name($type)),*
         }
 
+        /// Identifies a KOMSI command without its value.
+        ///
+        /// The discriminant is the command byte used on the wire.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+        #[repr(u8)]
+        pub enum KomsiCommandKind {
+            $($name = $char),*
+        }
+
+        impl KomsiCommandKind {
+            /// All command kinds in protocol table order.
+            pub const ALL: &'static [KomsiCommandKind] = &[$(Self::$name),*];
+
+            /// Returns the logical group of this command kind.
+            pub fn group(&self) -> CommandGroup {
+                match self {
+                    $(Self::$name => CommandGroup::$group),*
+                }
+            }
+
+            /// Returns the name of this command kind.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$name => stringify!($name)),*
+                }
+            }
+
+            /// Returns the name of the value type of this command kind.
+            pub fn value_type(&self) -> &'static str {
+                match self {
+                    $(Self::$name => stringify!($type)),*
+                }
+            }
+        }
+
+        impl TryFrom<u8> for KomsiCommandKind {
+            type Error = KomsiError;
+
+            /// Returns the command kind of a command byte read from the wire.
+            ///
+            /// Returns `KomsiError::UnknownCommand` with the byte if it is not a command byte.
+            fn try_from(byte: u8) -> Result<Self, Self::Error> {
+                match byte {
+                    $($char => Ok(Self::$name),)*
+                    _ => Err(KomsiError::UnknownCommand(byte)),
+                }
+            }
+        }
+
         impl KomsiCommand {
             /// Deserializes a command from a character and digits.
             pub fn from_parts(cmd_char: char, digits: &[u8]) -> Result<Self, KomsiError> {
@@ -177,6 +297,47 @@ macro_rules! define_komsi_commands {
                 }
             }
 
+            /// Creates a command of the given kind from a numeric value.
+            ///
+            /// Date/time values are given as YYYYMMDDHHMMSS.
+            /// Returns `KomsiError::InvalidValue` if the value does not fit the value type.
+            pub fn from_value(kind: KomsiCommandKind, value: u64) -> Result<Self, KomsiError> {
+                let mut digits = [0u8; 20];
+                let len = write_u64_to_buf(value, &mut digits);
+                Self::from_parts(kind as u8 as char, &digits[..len])
+            }
+
+            /// Returns the kind of this command.
+            pub fn kind(&self) -> KomsiCommandKind {
+                match self {
+                    $(Self::$name(_) => KomsiCommandKind::$name),*
+                }
+            }
+
+            /// Returns the logical group of this command.
+            pub fn group(&self) -> CommandGroup {
+                self.kind().group()
+            }
+
+            /// Returns the value of this command as a number.
+            ///
+            /// Booleans are 0 or 1, date/time values are given as YYYYMMDDHHMMSS.
+            pub fn value(&self) -> u64 {
+                match self {
+                    $(Self::$name(val) => <$type as RawValue>::raw_value(val)),*
+                }
+            }
+
+            /// Returns a copy of this command with `f` applied to its numeric value.
+            ///
+            /// The result saturates at the bounds of the value type.
+            /// Boolean and date/time commands are returned unchanged.
+            pub fn map_value(&self, f: &dyn Fn(u64) -> u64) -> Self {
+                match self {
+                    $(Self::$name(val) => Self::$name(<$type as MapRaw>::map_raw(*val, f))),*
+                }
+            }
+
             /// Serializes the command into the buffer.
             /// Returns the number of bytes written.
             pub fn to_packet(&self, buf: &mut [u8]) -> usize {
@@ -192,7 +353,7 @@ macro_rules! define_komsi_commands {
             }
 
             /// Creates a KOMSI command buffer from this command.
-            #[cfg(feature = "std")]
+            #[cfg(feature = "alloc")]
             pub fn build(&self) -> Vec<u8> {
                 // A buffer of 32 bytes is enough for all our types
                 // (u64 has max 20 digits, DateTime has 14).
@@ -208,12 +369,18 @@ macro_rules! define_komsi_commands {
             /// Builds a KOMSI EOL (End Of Line) command buffer.
             ///
             /// This simply contains the EOL byte.
-            #[cfg(feature = "std")]
+            #[cfg(feature = "alloc")]
             pub fn build_eol() -> Vec<u8> {
                 let cmd_u8 = 10u8;
                 let buffer: Vec<u8> = vec![cmd_u8];
                 buffer
             }
+
+            /// Builds an EOL buffer with the given line ending, e.g. `\r\n` for [`LineEnding::CrLf`].
+            #[cfg(feature = "alloc")]
+            pub fn build_eol_with(ending: LineEnding) -> Vec<u8> {
+                ending.as_bytes().to_vec()
+            }
         }
 
         impl core::str::FromStr for KomsiCommand {
@@ -221,7 +388,7 @@ macro_rules! define_komsi_commands {
 
             fn from_str(s: &str) -> Result<Self, Self::Err> {
                 let mut chars = s.chars();
-                let cmd_char = chars.next().ok_or(KomsiError::UnknownCommand)?;
+                let cmd_char = chars.next().ok_or(KomsiError::EmptyCommand)?;
                 let digits = chars.as_str().as_bytes();
                 Self::from_parts(cmd_char, digits)
             }
@@ -232,35 +399,564 @@ macro_rules! define_komsi_commands {
 // --- DEFINITION OF ALL COMMANDS (Central place, Single Source of Truth) ---
 
 define_komsi_commands! {
-    Ignition = b'A' => bool,
-    Engine = b'B' => bool,
-    PassengerDoorsOpen = b'C' => bool,
-    Indicator = b'D' => u8,
-    FixingBrake = b'E' => bool,
-    WarningLights = b'F' => bool,
-    MainLights = b'G' => bool,
-    FrontDoor = b'H' => bool,
-    SecondDoor = b'I' => bool,
-    ThirdDoor = b'J' => bool,
-    StopRequest = b'K' => bool,
-    StopBrake = b'L' => bool,
-    HighBeam = b'M' => bool,
-    BatteryLight = b'N' => bool,
-    SimulatorType = b'O' => u8,
-    DoorClearance = b'P' => bool,
-    DebugMode = b'd' => u32,
-    InfoRequest = b'i' => bool,
-    Odometer = b'o' => u64,
-    ProtocolSwitch = b'p' => u8,
-    DateTime = b'r' => KomsiDateTime,
-    MaxSpeed = b's' => u32,
-    RPM = b't' => u32,
-    Pressure = b'u' => u32,
-    Temperature = b'v' => u32,
-    Oil = b'w' => u32,
-    Fuel = b'x' => u8,
-    Speed = b'y' => u32,
-    Water = b'z' => u32
+    Ignition = b'A' => bool [Driving],
+    Engine = b'B' => bool [Driving],
+    PassengerDoorsOpen = b'C' => bool [Doors],
+    Indicator = b'D' => u8 [Driving],
+    FixingBrake = b'E' => bool [Brakes],
+    WarningLights = b'F' => bool [Warnings],
+    MainLights = b'G' => bool [Lights],
+    FrontDoor = b'H' => bool [Doors],
+    SecondDoor = b'I' => bool [Doors],
+    ThirdDoor = b'J' => bool [Doors],
+    StopRequest = b'K' => bool [Lights],
+    StopBrake = b'L' => bool [Brakes],
+    HighBeam = b'M' => bool [Lights],
+    BatteryLight = b'N' => bool [Warnings],
+    SimulatorType = b'O' => u8 [System],
+    DoorClearance = b'P' => bool [Doors],
+    Ramp = b'a' => bool [Doors],
+    StopRequestDoor = b'b' => u8 [Lights],
+    Timestamp = b'c' => u64 [System],
+    DebugMode = b'd' => u32 [System],
+    InfoRequest = b'i' => bool [System],
+    Odometer = b'o' => u64 [Driving],
+    ProtocolSwitch = b'p' => u8 [System],
+    DateTime = b'r' => KomsiDateTime [System],
+    MaxSpeed = b's' => u32 [Driving],
+    RPM = b't' => u32 [Sensors],
+    Pressure = b'u' => u32 [Sensors],
+    Temperature = b'v' => u32 [Sensors],
+    Oil = b'w' => u32 [Sensors],
+    Fuel = b'x' => u8 [Driving],
+    Speed = b'y' => u32 [Driving],
+    Water = b'z' => u32 [Sensors],
+    DoorChime = b'h' => bool [Doors],
+    Retarder = b'S' => u8 [Brakes],
+    Kneeling = b'T' => bool [Driving],
+    AirConditioning = b'U' => bool [Lights],
+    BrakeLamp = b'l' => bool [Brakes],
+    Passengers = b'X' => u32 [Driving],
+    SequenceNumber = b'Y' => u32 [System],
+    ReadyQuery = b'Z' => bool [System],
+    Neutral = b'e' => bool [Driving],
+    NextStop = b'n' => u32 [Driving],
+    GearSelector = b'g' => u8 [Driving],
+    FourthDoor = b'Q' => bool [Doors],
+    TripDistance = b'k' => u64 [Driving],
+    Checksum = b'j' => u8 [System],
+    Crc = b'q' => u8 [System]
+}
+
+/// The byte a board answers with once it is ready to receive commands.
+pub const READY_RESPONSE: u8 = 0x06;
+
+/// The line terminator sent after the commands of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LineEnding {
+    /// A single line feed (10), the KOMSI default.
+    #[default]
+    Lf,
+    /// Carriage return and line feed (13, 10).
+    CrLf,
+    /// A single carriage return (13).
+    ///
+    /// `KomsiDecoder` only splits lines at LF, so it can not read these frames.
+    Cr,
+}
+
+impl LineEnding {
+    /// Returns the bytes of this line ending.
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            Self::Lf => b"\n",
+            Self::CrLf => b"\r\n",
+            Self::Cr => b"\r",
+        }
+    }
+}
+
+impl KomsiCommand {
+    /// Serializes the commands in the given order into a single buffer terminated by EOL.
+    ///
+    /// Returns an empty buffer if there are no commands.
+    #[cfg(feature = "alloc")]
+    pub fn build_frame(commands: &[KomsiCommand]) -> Vec<u8> {
+        KomsiCommand::build_frame_with(commands, LineEnding::Lf)
+    }
+
+    /// Serializes the commands like [`KomsiCommand::build_frame`], terminated by `ending`.
+    #[cfg(feature = "alloc")]
+    pub fn build_frame_with(commands: &[KomsiCommand], ending: LineEnding) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        for cmd in commands {
+            buffer.extend_from_slice(&cmd.build());
+        }
+
+        // add end of line if buffer is not empty
+        if !buffer.is_empty() {
+            buffer.extend_from_slice(ending.as_bytes());
+        }
+
+        buffer
+    }
+
+    /// Builds a query asking the board whether it is ready to receive commands.
+    ///
+    /// The board answers with [`READY_RESPONSE`] once it is up.
+    #[cfg(feature = "alloc")]
+    pub fn build_ready_query() -> Vec<u8> {
+        let mut buffer = KomsiCommand::ReadyQuery(true).build();
+        buffer.append(&mut KomsiCommand::build_eol());
+        buffer
+    }
+}
+
+/// Decodes a single command received from the wire, e.g. `b"y50"`.
+///
+/// The first byte is the command kind and all remaining bytes are the ASCII digits of the
+/// value, without EOL. Returns
+/// - `KomsiError::EmptyCommand` for an empty slice,
+/// - `KomsiError::UnknownCommand` with an unknown command byte,
+/// - `KomsiError::EmptyValue` if there are no digits,
+/// - `KomsiError::InvalidDigit` with the first byte that is not a digit,
+/// - `KomsiError::ValueOverflow` if the value does not fit the value type and
+/// - `KomsiError::InvalidDateTime` for malformed date/time values.
+pub fn decode_komsi_command(bytes: &[u8]) -> Result<KomsiCommand, KomsiError> {
+    let (&cmd, digits) = bytes.split_first().ok_or(KomsiError::EmptyCommand)?;
+    let kind = KomsiCommandKind::try_from(cmd)?;
+    if digits.is_empty() {
+        return Err(KomsiError::EmptyValue(kind));
+    }
+    if let Some(&b) = digits.iter().find(|b| !b.is_ascii_digit()) {
+        return Err(KomsiError::InvalidDigit(b));
+    }
+    // all digits are valid, so an invalid value is out of range
+    KomsiCommand::from_parts(kind as u8 as char, digits).map_err(|e| match e {
+        KomsiError::InvalidValue => KomsiError::ValueOverflow,
+        e => e,
+    })
+}
+
+/// Decodes all commands of a line received from the wire, e.g. `b"A1y50\n"`.
+///
+/// Every command ends where the next command byte starts. Decoding stops at the first EOL
+/// or carriage return (see [`LineEnding`]), a line without EOL is decoded up to its end. Errors are returned as in
+/// [`decode_komsi_command`], e.g. `KomsiError::EmptyValue` for a command without value.
+#[cfg(feature = "std")]
+pub fn decode_komsi_line(bytes: &[u8]) -> Result<Vec<KomsiCommand>, KomsiError> {
+    let line = match bytes.iter().position(|&b| b == b'\n' || b == b'\r') {
+        Some(eol) => &bytes[..eol],
+        None => bytes,
+    };
+
+    let mut commands = Vec::new();
+    let mut start = 0;
+    while start < line.len() {
+        let end = (start + 1..line.len())
+            .find(|&i| KomsiCommandKind::try_from(line[i]).is_ok())
+            .unwrap_or(line.len());
+        commands.push(decode_komsi_command(&line[start..end])?);
+        start = end;
+    }
+    Ok(commands)
+}
+
+/// Collects bytes from partial reads and decodes complete lines, e.g. for serial links.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct KomsiDecoder {
+    /// Received bytes that do not form a complete line yet.
+    buffer: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl KomsiDecoder {
+    /// Creates a new decoder with an empty buffer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends received bytes to the buffer.
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Removes the next complete line from the buffer and decodes it like [`decode_komsi_line`].
+    ///
+    /// Returns `None` if there is no complete line yet; the bytes after the last EOL are kept
+    /// for the next `push`. A line that can not be decoded is removed and its error returned.
+    pub fn next_line(&mut self) -> Option<Result<Vec<KomsiCommand>, KomsiError>> {
+        let eol = self.buffer.iter().position(|&b| b == 10)?;
+        let line: Vec<u8> = self.buffer.drain(..=eol).collect();
+        Some(decode_komsi_line(&line))
+    }
+}
+
+/// The command byte of text messages, see [`build_text_command`].
+pub const TEXT_COMMAND: u8 = b'V';
+
+/// The maximum number of characters of a text message.
+pub const MAX_TEXT_LEN: usize = 32;
+
+/// Builds a frame that shows the text in the given display slot, e.g. a route destination.
+///
+/// The text is sent on the reserved code `V` (firmware version 2). The slot and then every
+/// character are encoded as 3 decimal digits each, so the payload only contains digits like
+/// every other command, e.g. slot 1 with `"Hi"` becomes `V001072105`.
+/// Returns `KomsiError::InvalidValue` if the text is longer than [`MAX_TEXT_LEN`] or contains
+/// characters other than printable ASCII. An empty text clears the slot.
+#[cfg(feature = "alloc")]
+pub fn build_text_command(text: &str, slot: u8) -> Result<Vec<u8>, KomsiError> {
+    if text.len() > MAX_TEXT_LEN || !text.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+        return Err(KomsiError::InvalidValue);
+    }
+    let mut buffer = vec![TEXT_COMMAND];
+    for b in core::iter::once(slot).chain(text.bytes()) {
+        buffer.extend_from_slice(format!("{:03}", b).as_bytes());
+    }
+    buffer.append(&mut KomsiCommand::build_eol());
+    Ok(buffer)
+}
+
+/// The common "no data" marker for [`build_optional_command`].
+pub const NO_DATA: &str = "---";
+
+/// Builds a single command without EOL whose value may be absent, e.g. an invalid fuel reading.
+///
+/// `Some` value is sent as usual, `None` is sent as the command byte followed by `sentinel`
+/// (e.g. [`NO_DATA`]) instead of the digits, so the board can show that there is no data.
+/// Returns `KomsiError::InvalidValue` if the value does not fit the command or the sentinel
+/// is empty or contains anything but printable ASCII punctuation and spaces, as digits and
+/// letters would be read as a value or as the next command.
+#[cfg(feature = "alloc")]
+pub fn build_optional_command(
+    kind: KomsiCommandKind,
+    value: Option<u32>,
+    sentinel: &str,
+) -> Result<Vec<u8>, KomsiError> {
+    if let Some(value) = value {
+        return Ok(KomsiCommand::from_value(kind, value as u64)?.build());
+    }
+    let valid = |b: u8| b == b' ' || b.is_ascii_punctuation();
+    if sentinel.is_empty() || !sentinel.bytes().all(valid) {
+        return Err(KomsiError::InvalidValue);
+    }
+    let mut buffer = vec![kind as u8];
+    buffer.extend_from_slice(sentinel.as_bytes());
+    Ok(buffer)
+}
+
+/// Returns the additive checksum of the command bytes of a line (without EOL).
+///
+/// The checksum is the sum of all bytes modulo 256, i.e. all bytes added up in a `u8`
+/// that wraps around on overflow. With [`Framing::Checksum`] it is sent as the decimal value
+/// of a `Checksum` command after the other commands, e.g. `y55j227`, so it can never be
+/// mistaken for EOL or another command.
+pub fn line_checksum(commands: &[u8]) -> u8 {
+    commands.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// An integrity check appended to the command bytes of each line, before the EOL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Framing {
+    /// No integrity check, the KOMSI default.
+    #[default]
+    None,
+    /// A `Checksum` command with the additive checksum of the command bytes,
+    /// see [`line_checksum`].
+    Checksum,
+    /// A `Crc` command with the CRC-8 of the command bytes, see [`crc8`].
+    Crc8,
+}
+
+impl Framing {
+    /// Serializes the commands in the given order with this integrity check into a single
+    /// buffer terminated by `ending`.
+    ///
+    /// Returns an empty buffer if there are no commands.
+    #[cfg(feature = "alloc")]
+    pub fn build_frame(&self, commands: &[KomsiCommand], ending: LineEnding) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        for cmd in commands {
+            buffer.extend_from_slice(&cmd.build());
+        }
+        if buffer.is_empty() {
+            return buffer;
+        }
+
+        match self {
+            Self::None => {}
+            Self::Checksum => {
+                let checksum = KomsiCommand::Checksum(line_checksum(&buffer));
+                buffer.extend_from_slice(&checksum.build());
+            }
+            Self::Crc8 => {
+                let crc = KomsiCommand::Crc(crc8(&buffer));
+                buffer.extend_from_slice(&crc.build());
+            }
+        }
+        buffer.extend_from_slice(ending.as_bytes());
+        buffer
+    }
+
+    /// Checks the integrity check of a received line, with or without its line ending.
+    ///
+    /// The check must be the last command of the line. Always true for [`Framing::None`].
+    pub fn verify(&self, line: &[u8]) -> bool {
+        let (kind, check): (KomsiCommandKind, fn(&[u8]) -> u8) = match self {
+            Self::None => return true,
+            Self::Checksum => (KomsiCommandKind::Checksum, line_checksum),
+            Self::Crc8 => (KomsiCommandKind::Crc, crc8),
+        };
+        split_check(line, kind).is_some_and(|(commands, value)| check(commands) == value)
+    }
+
+    /// Verifies a received line like [`Framing::verify`] and decodes its commands without
+    /// the integrity check, see [`decode_komsi_line`].
+    ///
+    /// Returns `KomsiError::ChecksumMismatch` if the check is missing or does not match.
+    #[cfg(feature = "std")]
+    pub fn decode_line(&self, line: &[u8]) -> Result<Vec<KomsiCommand>, KomsiError> {
+        let kind = match self {
+            Self::None => return decode_komsi_line(line),
+            Self::Checksum => KomsiCommandKind::Checksum,
+            Self::Crc8 => KomsiCommandKind::Crc,
+        };
+        match split_check(line, kind) {
+            Some((commands, _)) if self.verify(line) => decode_komsi_line(commands),
+            _ => Err(KomsiError::ChecksumMismatch),
+        }
+    }
+}
+
+/// Splits a line into the command bytes and the value of the trailing check command
+/// of the given kind, ignoring the line ending.
+fn split_check(line: &[u8], kind: KomsiCommandKind) -> Option<(&[u8], u8)> {
+    let end = line.iter().rposition(|&b| b != b'\n' && b != b'\r')? + 1;
+    let line = &line[..end];
+    let digits = line.iter().rev().take_while(|b| b.is_ascii_digit()).count();
+    let start = line.len().checked_sub(digits + 1)?;
+    if digits == 0 || line[start] != kind as u8 {
+        return None;
+    }
+    match KomsiCommand::from_parts(kind as u8 as char, &line[start + 1..]) {
+        Ok(KomsiCommand::Checksum(v) | KomsiCommand::Crc(v)) => Some((&line[..start], v)),
+        _ => None,
+    }
+}
+
+/// Returns the CRC-8 of the command bytes of a line (without EOL).
+///
+/// Polynomial 0x07, initial value 0, no reflection and no final XOR (CRC-8/SMBUS),
+/// e.g. `b"123456789"` gives 0xf4.
+pub fn crc8(commands: &[u8]) -> u8 {
+    commands.iter().fold(0u8, |mut crc, &b| {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// Checks a received line framed with [`Framing::Crc8`], with or without its line ending.
+///
+/// Returns false if the line does not end with a `Crc` command or the CRC does not match.
+/// Use [`Framing::decode_line`] to decode the commands of a checked line.
+pub fn verify_crc8(line: &[u8]) -> bool {
+    Framing::Crc8.verify(line)
+}
+
+/// Writes a single command without EOL into `out` without allocating and returns its length.
+///
+/// The bytes are the same as built by [`KomsiCommand::build`].
+/// Returns `KomsiError::InvalidValue` if the value does not fit the command and
+/// `KomsiError::BufferTooSmall` if `out` is too small for the command byte and all digits.
+pub fn build_komsi_command_into(
+    cmd: KomsiCommandKind,
+    value: u32,
+    out: &mut [u8],
+) -> Result<usize, KomsiError> {
+    let mut buffer = [0u8; 32];
+    let len = KomsiCommand::from_value(cmd, value as u64)?.to_packet(&mut buffer);
+    let out = out.get_mut(..len).ok_or(KomsiError::BufferTooSmall)?;
+    out.copy_from_slice(&buffer[..len]);
+    Ok(len)
+}
+
+/// Serializes hand-assembled commands in the given order into a single buffer terminated
+/// by EOL, like [`KomsiCommand::build_frame`].
+///
+/// Every value is checked by [`build_komsi_command_into`], e.g. `(KomsiCommandKind::Speed, 50)`
+/// is sent as `y50`. Returns an empty buffer if there are no commands.
+///
+/// # Panics
+///
+/// Panics if a value does not fit its command, e.g. 300 for a `u8` command or any value
+/// for `DateTime`.
+#[cfg(feature = "alloc")]
+pub fn build_frame(commands: &[(KomsiCommandKind, u32)]) -> Vec<u8> {
+    let commands: Vec<KomsiCommand> = commands
+        .iter()
+        .map(|&(kind, value)| {
+            let mut packet = [0u8; 32];
+            let len = build_komsi_command_into(kind, value, &mut packet)
+                .unwrap_or_else(|err| panic!("invalid value {value} for {kind}: {err:?}"));
+            decode_komsi_command(&packet[..len]).expect("a built command decodes")
+        })
+        .collect();
+    KomsiCommand::build_frame(&commands)
+}
+
+/// Returns a text table of all commands with their byte, character, name, value type and group.
+#[cfg(feature = "std")]
+pub fn protocol_table() -> String {
+    let mut table = format!(
+        "{:<4}  {:<4}  {:<18}  {:<13}  {}\n",
+        "byte", "char", "name", "type", "group"
+    );
+    for kind in KomsiCommandKind::ALL {
+        table.push_str(&format!(
+            "{:<4}  {:<4}  {:<18}  {:<13}  {:?}\n",
+            *kind as u8,
+            *kind as u8 as char,
+            kind.name(),
+            kind.value_type(),
+            kind.group()
+        ));
+    }
+    table
+}
+
+/// Returns true if the bytes received from the board contain the ready response.
+pub fn is_ready_response(response: &[u8]) -> bool {
+    response.contains(&READY_RESPONSE)
+}
+
+/// Formats a command kind as its name, e.g. `Speed`.
+impl core::fmt::Display for KomsiCommandKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl core::str::FromStr for KomsiCommandKind {
+    type Err = KomsiError;
+
+    /// Parses a command kind from its name, ignoring case, e.g. `"speed"`.
+    ///
+    /// Returns `KomsiError::UnknownName` if no command kind has this name.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        KomsiCommandKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name().eq_ignore_ascii_case(name))
+            .ok_or(KomsiError::UnknownName)
+    }
+}
+
+/// Serializes a command kind as its name, e.g. `"Speed"`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for KomsiCommandKind {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// Deserializes a command kind from its name, see the [`FromStr`](core::str::FromStr) impl.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for KomsiCommandKind {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NameVisitor;
+
+        impl serde::de::Visitor<'_> for NameVisitor {
+            type Value = KomsiCommandKind;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.write_str("a KOMSI command name")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<Self::Value, E> {
+                name.parse()
+                    .map_err(|_| E::custom(format_args!("unknown command name {name}")))
+            }
+        }
+
+        deserializer.deserialize_str(NameVisitor)
+    }
+}
+
+impl KomsiCommandKind {
+    /// Returns the command kinds that have to be sent before this one in the same frame.
+    ///
+    /// The door commands depend on the door clearance, so the board releases the doors first.
+    pub fn dependencies(&self) -> &'static [KomsiCommandKind] {
+        match self {
+            Self::PassengerDoorsOpen
+            | Self::FrontDoor
+            | Self::SecondDoor
+            | Self::ThirdDoor
+            | Self::FourthDoor => &[Self::DoorClearance],
+            _ => &[],
+        }
+    }
+}
+
+impl KomsiCommandKind {
+    /// Returns the first board firmware version that supports this command kind.
+    ///
+    /// Version 1 is the original KOMSI command set, commands added later on the reserved
+    /// or free codes need version 2.
+    pub fn min_firmware_version(&self) -> u16 {
+        match self {
+            Self::Ramp
+            | Self::StopRequestDoor
+            | Self::Timestamp
+            | Self::DoorChime
+            | Self::Retarder
+            | Self::Kneeling
+            | Self::AirConditioning
+            | Self::BrakeLamp
+            | Self::Passengers
+            | Self::SequenceNumber
+            | Self::ReadyQuery
+            | Self::Neutral
+            | Self::NextStop
+            | Self::GearSelector
+            | Self::FourthDoor
+            | Self::TripDistance
+            | Self::Checksum
+            | Self::Crc => 2,
+            _ => 1,
+        }
+    }
+
+    /// Returns true if a board with the given firmware version supports this command kind.
+    pub fn is_supported_by(&self, firmware_version: u16) -> bool {
+        firmware_version >= self.min_firmware_version()
+    }
+}
+
+/// Reorders the commands so every command comes after its dependencies.
+///
+/// The order of commands without dependencies between them is preserved.
+pub fn sort_by_dependencies(commands: &mut [KomsiCommand]) {
+    let mut i = 0;
+    while i < commands.len() {
+        let deps = commands[i].kind().dependencies();
+        match (i + 1..commands.len()).find(|&j| deps.contains(&commands[j].kind())) {
+            // move the dependency in front and check it again for its own dependencies
+            Some(j) => commands[i..=j].rotate_right(1),
+            None => i += 1,
+        }
+    }
 }
 
 // --- HELPER FUNCTIONS FOR PARSING & FORMATTING ---
@@ -346,7 +1042,7 @@ fn write_fixed_u16(n: u16, buf: &mut [u8]) {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
 
@@ -442,6 +1138,285 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode_komsi_command() {
+        let commands = [
+            KomsiCommand::Ignition(true),
+            KomsiCommand::Speed(50),
+            KomsiCommand::Odometer(123456789),
+            KomsiCommand::DateTime(KomsiDateTime {
+                year: 2024,
+                month: 12,
+                day: 24,
+                hour: 18,
+                min: 30,
+                sec: 5,
+            }),
+        ];
+        for cmd in commands {
+            let mut buf = [0u8; 32];
+            let len = cmd.to_packet(&mut buf);
+            assert_eq!(decode_komsi_command(&buf[..len]), Ok(cmd));
+        }
+
+        assert_eq!(decode_komsi_command(b""), Err(KomsiError::EmptyCommand));
+        assert_eq!(
+            decode_komsi_command(b"#5"),
+            Err(KomsiError::UnknownCommand(b'#'))
+        );
+        assert_eq!(
+            decode_komsi_command(b"y"),
+            Err(KomsiError::EmptyValue(KomsiCommandKind::Speed))
+        );
+        assert_eq!(
+            decode_komsi_command(b"y5a"),
+            Err(KomsiError::InvalidDigit(b'a'))
+        );
+        assert_eq!(
+            decode_komsi_command(b"y50\n"),
+            Err(KomsiError::InvalidDigit(b'\n'))
+        );
+        assert_eq!(
+            decode_komsi_command(b"x300"),
+            Err(KomsiError::ValueOverflow)
+        );
+        assert_eq!(
+            decode_komsi_command(b"r2024"),
+            Err(KomsiError::InvalidDateTime)
+        );
+    }
+
+    #[test]
+    fn test_decode_komsi_line() {
+        let line = decode_komsi_line(&[65, 49, 121, 53, 48, 10]).unwrap();
+        assert_eq!(
+            line,
+            [KomsiCommand::Ignition(true), KomsiCommand::Speed(50)]
+        );
+
+        // bytes after the EOL belong to the next line
+        assert_eq!(
+            decode_komsi_line(b"x5\ny7\n"),
+            Ok(vec![KomsiCommand::Fuel(5)])
+        );
+        assert_eq!(decode_komsi_line(b"\n"), Ok(Vec::new()));
+        assert_eq!(
+            decode_komsi_line(b"A1y\n"),
+            Err(KomsiError::EmptyValue(KomsiCommandKind::Speed))
+        );
+        assert_eq!(
+            decode_komsi_line(b"5y5\n"),
+            Err(KomsiError::UnknownCommand(b'5'))
+        );
+
+        let mut state = crate::vehicle::VehicleState::new();
+        state.speed = 30;
+        state.datetime.min = 15;
+        let frame = crate::vehicle::VehicleState::new().compare(&state, true, None);
+        let decoded = decode_komsi_line(&frame).unwrap();
+        assert!(decoded.contains(&KomsiCommand::Speed(30)));
+        assert_eq!(decoded, state.compare_commands(&state, true, None));
+    }
+
+    #[test]
+    fn test_komsi_decoder() {
+        let mut decoder = KomsiDecoder::new();
+        decoder.push(b"A1y");
+        assert_eq!(decoder.next_line(), None);
+        decoder.push(b"5");
+        assert_eq!(decoder.next_line(), None);
+        decoder.push(b"0\nx4");
+
+        let line = decoder.next_line().unwrap();
+        assert_eq!(
+            line,
+            Ok(vec![KomsiCommand::Ignition(true), KomsiCommand::Speed(50)])
+        );
+        assert_eq!(decoder.next_line(), None);
+
+        // the rest of the chunk is kept for the next line
+        decoder.push(b"\ny\n");
+        assert_eq!(decoder.next_line(), Some(Ok(vec![KomsiCommand::Fuel(4)])));
+        assert_eq!(
+            decoder.next_line(),
+            Some(Err(KomsiError::EmptyValue(KomsiCommandKind::Speed)))
+        );
+        assert_eq!(decoder.next_line(), None);
+    }
+
+    #[test]
+    fn test_build_optional_command() {
+        let fuel = KomsiCommandKind::Fuel;
+        assert_eq!(
+            build_optional_command(fuel, Some(42), NO_DATA),
+            Ok(b"x42".to_vec())
+        );
+        assert_eq!(
+            build_optional_command(fuel, None, NO_DATA),
+            Ok(b"x---".to_vec())
+        );
+        assert_eq!(build_optional_command(fuel, None, "?"), Ok(b"x?".to_vec()));
+
+        for sentinel in ["", "n/a", "-1", "--\n"] {
+            assert_eq!(
+                build_optional_command(fuel, None, sentinel),
+                Err(KomsiError::InvalidValue),
+                "{sentinel:?}"
+            );
+        }
+        // fuel is a u8
+        assert!(build_optional_command(fuel, Some(300), NO_DATA).is_err());
+    }
+
+    #[test]
+    fn test_build_text_command() {
+        assert_eq!(build_text_command("Hi", 1), Ok(b"V001072105\n".to_vec()));
+        assert_eq!(build_text_command("", 12), Ok(b"V012\n".to_vec()));
+
+        let longest = "x".repeat(MAX_TEXT_LEN);
+        assert!(build_text_command(&longest, 0).is_ok());
+        assert_eq!(
+            build_text_command(&format!("{longest}x"), 0),
+            Err(KomsiError::InvalidValue)
+        );
+        assert_eq!(build_text_command("Bf\n", 0), Err(KomsiError::InvalidValue));
+        assert_eq!(
+            build_text_command("Zürich", 0),
+            Err(KomsiError::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn test_command_kind_names() {
+        assert_eq!(
+            "Speed".parse::<KomsiCommandKind>(),
+            Ok(KomsiCommandKind::Speed)
+        );
+        assert_eq!("mainLIGHTS".parse(), Ok(KomsiCommandKind::MainLights));
+        assert_eq!(
+            "Warp".parse::<KomsiCommandKind>(),
+            Err(KomsiError::UnknownName)
+        );
+        for &kind in KomsiCommandKind::ALL {
+            assert_eq!(kind.to_string().parse(), Ok(kind));
+        }
+        assert_eq!(format!("{}", KomsiCommandKind::RPM), "RPM");
+    }
+
+    #[test]
+    fn test_command_kind() {
+        assert_eq!(KomsiCommand::Speed(120).kind(), KomsiCommandKind::Speed);
+        assert_eq!(KomsiCommandKind::Speed as u8, b'y');
+        assert_eq!(KomsiCommandKind::ALL.len(), 47);
+        assert_eq!(KomsiCommandKind::FixingBrake.group(), CommandGroup::Brakes);
+    }
+
+    #[test]
+    fn test_command_kind_try_from() {
+        for &kind in KomsiCommandKind::ALL {
+            assert_eq!(KomsiCommandKind::try_from(kind as u8), Ok(kind));
+        }
+        for byte in 0..=u8::MAX {
+            if !KomsiCommandKind::ALL.iter().any(|&k| k as u8 == byte) {
+                assert_eq!(
+                    KomsiCommandKind::try_from(byte),
+                    Err(KomsiError::UnknownCommand(byte))
+                );
+            }
+        }
+        assert_eq!(
+            KomsiCommandKind::try_from(b'y'),
+            Ok(KomsiCommandKind::Speed)
+        );
+        assert!(KomsiCommandKind::try_from(10).is_err()); // EOL is no command kind
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_command_kind_serde() {
+        assert_eq!(
+            serde_json::to_string(&KomsiCommandKind::MainLights).unwrap(),
+            "\"MainLights\""
+        );
+        for &kind in KomsiCommandKind::ALL {
+            let json = serde_json::to_string(&kind).unwrap();
+            assert_eq!(
+                serde_json::from_str::<KomsiCommandKind>(&json).unwrap(),
+                kind
+            );
+        }
+        assert!(serde_json::from_str::<KomsiCommandKind>("\"Warp\"").is_err());
+        assert!(serde_json::from_str::<KomsiCommandKind>("121").is_err());
+    }
+
+    #[test]
+    fn test_sort_by_dependencies() {
+        let mut commands = [
+            KomsiCommand::Speed(10),
+            KomsiCommand::FrontDoor(true),
+            KomsiCommand::SecondDoor(true),
+            KomsiCommand::DoorClearance(true),
+            KomsiCommand::Fuel(5),
+        ];
+        sort_by_dependencies(&mut commands);
+        assert_eq!(
+            commands,
+            [
+                KomsiCommand::Speed(10),
+                KomsiCommand::DoorClearance(true),
+                KomsiCommand::FrontDoor(true),
+                KomsiCommand::SecondDoor(true),
+                KomsiCommand::Fuel(5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_firmware_version() {
+        assert!(KomsiCommandKind::Speed.is_supported_by(1));
+        assert!(!KomsiCommandKind::SequenceNumber.is_supported_by(1));
+        assert!(KomsiCommandKind::SequenceNumber.is_supported_by(2));
+    }
+
+    #[test]
+    fn test_value() {
+        assert_eq!(KomsiCommand::Speed(120).value(), 120);
+        assert_eq!(KomsiCommand::Ignition(true).value(), 1);
+        let dt = KomsiDateTime::from_iso("2026-02-26T16:45:07").unwrap();
+        assert_eq!(KomsiCommand::DateTime(dt).value(), 20260226164507);
+    }
+
+    #[test]
+    fn test_from_value() {
+        assert_eq!(
+            KomsiCommand::from_value(KomsiCommandKind::Speed, 50),
+            Ok(KomsiCommand::Speed(50))
+        );
+        assert_eq!(
+            KomsiCommand::from_value(KomsiCommandKind::Fuel, 300),
+            Err(KomsiError::InvalidValue)
+        );
+        let dt = KomsiCommand::from_value(KomsiCommandKind::DateTime, 20260226164507).unwrap();
+        assert_eq!(dt.value(), 20260226164507);
+    }
+
+    #[test]
+    fn test_map_value() {
+        let double = |v: u64| v * 2;
+        assert_eq!(
+            KomsiCommand::Speed(60).map_value(&double),
+            KomsiCommand::Speed(120)
+        );
+        assert_eq!(
+            KomsiCommand::Fuel(200).map_value(&double),
+            KomsiCommand::Fuel(255)
+        ); // saturates
+        assert_eq!(
+            KomsiCommand::Ignition(true).map_value(&double),
+            KomsiCommand::Ignition(true)
+        );
+    }
+
     #[test]
     fn test_build_komsi_command() {
         let cmd = KomsiCommand::Speed(120);
@@ -449,9 +1424,190 @@ mod tests {
         assert_eq!(buf, b"y120");
     }
 
+    #[test]
+    fn test_build_komsi_command_into() {
+        let mut out = [0u8; 16];
+        for &kind in KomsiCommandKind::ALL {
+            for value in [0, 1, 7, 42, 255, 65_535, u32::MAX] {
+                match KomsiCommand::from_value(kind, value as u64) {
+                    Ok(cmd) => {
+                        let len = build_komsi_command_into(kind, value, &mut out).unwrap();
+                        assert_eq!(out[..len], cmd.build()[..]);
+                    }
+                    Err(err) => {
+                        assert_eq!(build_komsi_command_into(kind, value, &mut out), Err(err))
+                    }
+                }
+            }
+        }
+
+        let mut small = [0u8; 3];
+        assert_eq!(
+            build_komsi_command_into(KomsiCommandKind::Speed, 120, &mut small),
+            Err(KomsiError::BufferTooSmall)
+        );
+        assert_eq!(
+            build_komsi_command_into(KomsiCommandKind::Fuel, 256, &mut out),
+            Err(KomsiError::InvalidValue)
+        );
+        assert_eq!(
+            build_komsi_command_into(KomsiCommandKind::Speed, 12, &mut small),
+            Ok(3)
+        );
+        assert_eq!(&small, b"y12");
+    }
+
+    #[test]
+    fn test_build_frame_from_values() {
+        let frame = build_frame(&[
+            (KomsiCommandKind::Speed, 50),
+            (KomsiCommandKind::Ignition, 1),
+            (KomsiCommandKind::Speed, 55),
+        ]);
+        assert_eq!(frame, b"y50A1y55\n");
+        assert!(build_frame(&[]).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value 300 for Retarder")]
+    fn test_build_frame_from_values_invalid() {
+        build_frame(&[(KomsiCommandKind::Retarder, 300)]);
+    }
+
+    #[test]
+    fn test_write_u64_to_buf() {
+        let mut buf = [0u8; 20];
+        for n in [0, 1, 9, 10, 99, 100, 12_345, u32::MAX as u64, u64::MAX] {
+            let len = write_u64_to_buf(n, &mut buf);
+            assert_eq!(&buf[..len], format!("{n}").as_bytes());
+        }
+
+        let mut short = [0u8; 2];
+        assert_eq!(write_u64_to_buf(120, &mut short), 2);
+        assert_eq!(&short, b"12");
+        assert_eq!(write_u64_to_buf(7, &mut []), 0);
+    }
+
+    #[test]
+    fn test_protocol_table() {
+        let table = protocol_table();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), KomsiCommandKind::ALL.len() + 1);
+        assert_eq!(
+            lines[1],
+            "65    A     Ignition            bool           Driving"
+        );
+        assert!(lines.contains(&"114   r     DateTime            KomsiDateTime  System"));
+    }
+
+    #[test]
+    fn test_build_frame() {
+        let frame = KomsiCommand::build_frame(&[
+            KomsiCommand::Speed(50),
+            KomsiCommand::Ignition(true),
+            KomsiCommand::Fuel(7),
+        ]);
+        assert_eq!(frame, b"y50A1x7\n");
+        assert!(KomsiCommand::build_frame(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_line_checksum() {
+        // 65 + 49 + 121 + 53 + 48 = 336 = 80 (mod 256)
+        assert_eq!(line_checksum(b"A1y50"), 80);
+        assert_eq!(line_checksum(b""), 0);
+        assert_eq!(line_checksum(&[0xff, 0x02]), 1);
+
+        let commands = [KomsiCommand::Ignition(true), KomsiCommand::Speed(50)];
+        let frame = Framing::Checksum.build_frame(&commands, LineEnding::Lf);
+        assert_eq!(frame, b"A1y50j80\n");
+        assert_eq!(
+            decode_komsi_line(&frame).unwrap().last(),
+            Some(&KomsiCommand::Checksum(80))
+        );
+        assert_eq!(
+            Framing::Checksum.build_frame(&commands, LineEnding::CrLf),
+            b"A1y50j80\r\n"
+        );
+        assert_eq!(
+            Framing::None.build_frame(&commands, LineEnding::Lf),
+            KomsiCommand::build_frame(&commands)
+        );
+        assert!(
+            Framing::Checksum
+                .build_frame(&[], LineEnding::Lf)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_crc8() {
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc8(b""), 0);
+
+        let commands = [KomsiCommand::Ignition(true), KomsiCommand::Speed(50)];
+        let line = Framing::Crc8.build_frame(&commands, LineEnding::Lf);
+        assert_eq!(line, format!("A1y50q{}\n", crc8(b"A1y50")).as_bytes());
+        assert!(verify_crc8(&line));
+        assert!(verify_crc8(&line[..line.len() - 1]));
+        assert_eq!(Framing::Crc8.decode_line(&line), Ok(commands.to_vec()));
+
+        // a single flipped bit is always detected
+        for i in 0..line.len() * 8 {
+            let mut corrupted = line.clone();
+            corrupted[i / 8] ^= 1 << (i % 8);
+            assert!(!verify_crc8(&corrupted));
+            assert!(Framing::Crc8.decode_line(&corrupted).is_err());
+        }
+        assert!(!verify_crc8(b""));
+        assert!(!verify_crc8(b"A1y50\n"));
+        assert_eq!(
+            Framing::Crc8.decode_line(b"A1y50\n"),
+            Err(KomsiError::ChecksumMismatch)
+        );
+
+        let line = Framing::Checksum.build_frame(&commands, LineEnding::CrLf);
+        assert!(Framing::Checksum.verify(&line));
+        assert!(!Framing::Crc8.verify(&line));
+        assert_eq!(Framing::Checksum.decode_line(&line), Ok(commands.to_vec()));
+        assert!(Framing::None.verify(b"A1y50\n"));
+    }
+
+    #[test]
+    fn test_line_ending() {
+        let commands = [KomsiCommand::Ignition(true), KomsiCommand::Speed(50)];
+        assert_eq!(
+            KomsiCommand::build_frame_with(&commands, LineEnding::default()),
+            KomsiCommand::build_frame(&commands)
+        );
+        let frame = KomsiCommand::build_frame_with(&commands, LineEnding::CrLf);
+        assert_eq!(frame, b"A1y50\r\n");
+        assert!(frame.ends_with(&[13, 10]));
+        assert_eq!(
+            KomsiCommand::build_frame_with(&commands, LineEnding::Cr),
+            b"A1y50\r"
+        );
+        assert!(KomsiCommand::build_frame_with(&[], LineEnding::CrLf).is_empty());
+        assert_eq!(KomsiCommand::build_eol_with(LineEnding::CrLf), [13, 10]);
+        assert_eq!(decode_komsi_line(b"A1y50\r\n"), Ok(commands.to_vec()));
+    }
+
+    #[test]
+    fn test_ready_query() {
+        assert_eq!(KomsiCommand::build_ready_query(), b"Z1\n");
+        assert!(is_ready_response(&[READY_RESPONSE]));
+        assert!(is_ready_response(b"\r\x06"));
+        assert!(!is_ready_response(b""));
+        assert!(!is_ready_response(b"Z0"));
+    }
+
     #[test]
     fn test_invalid_parse() {
         assert!(parse_u64(b"12a").is_err());
+        assert_eq!(
+            "x256".parse::<KomsiCommand>(),
+            Err(KomsiError::InvalidValue)
+        ); // u8 overflow
         assert!(parse_datetime(b"2024010112000").is_err()); // Too short
     }
 
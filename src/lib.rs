@@ -5,13 +5,59 @@
 //! in simulators like "The Bus" or "OMSI 2" and can be used in std and non-std environments.
 //!
 //! It includes structures for tracking vehicle state and functions for building and decoding KOMSI commands.
+//!
+//! Without the default `std` feature the crate is `no_std`. The `alloc` feature then enables
+//! the command builders that return a `Vec`, e.g. for firmware with an allocator.
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
+/// Error type of the KOMSI protocol.
+pub mod error;
 /// KOMSI protocol command types and builders.
 pub mod komsi;
 /// Vehicle state tracking and comparison.
 pub mod vehicle;
+/// Compact encodings for recorded command streams.
+#[cfg(feature = "std")]
+pub mod recording;
+/// Compact binary encodings of the vehicle state.
+pub mod binary;
+/// CSV encoding of the vehicle state.
+#[cfg(feature = "std")]
+pub mod csv;
+/// Board-specific adjustments of the command encoding.
+#[cfg(feature = "std")]
+pub mod dialect;
+/// Session tracking of the state sent to a board.
+#[cfg(feature = "std")]
+pub mod session;
+/// Sending of frames to a board.
+#[cfg(feature = "std")]
+pub mod transport;
+
+#[cfg(all(test, not(feature = "std")))]
+mod no_std_smoke;
 
+pub use komsi::CommandGroup;
+#[cfg(feature = "std")]
+pub use dialect::Dialect;
 pub use komsi::KomsiDateTime;
 pub use komsi::KomsiCommand;
-pub use komsi::KomsiError;
+pub use komsi::KomsiCommandKind;
+pub use komsi::LineEnding;
+pub use komsi::Framing;
+pub use error::KomsiError;
+#[cfg(feature = "std")]
+pub use session::CommandCache;
+#[cfg(feature = "std")]
+pub use session::KomsiSession;
+#[cfg(feature = "std")]
+pub use transport::FrameRing;
+#[cfg(feature = "std")]
+pub use transport::Transport;
+#[cfg(feature = "std")]
+pub use transport::UdpSink;
+pub use vehicle::GearSelector;
+pub use vehicle::Indicator;
 pub use vehicle::VehicleState;
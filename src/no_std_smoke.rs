@@ -0,0 +1,60 @@
+//! Smoke tests of the `no_std` build, run with
+//! `cargo test --no-default-features --features alloc`.
+
+use crate::komsi::{KomsiCommand, KomsiCommandKind, decode_komsi_command};
+use crate::vehicle::VehicleState;
+
+#[test]
+fn test_to_packet() {
+    let mut buf = [0u8; 32];
+    let len = KomsiCommand::Speed(50).to_packet(&mut buf);
+    assert_eq!(&buf[..len], b"y50");
+    assert_eq!(
+        decode_komsi_command(&buf[..len]),
+        Ok(KomsiCommand::Speed(50))
+    );
+}
+
+#[test]
+fn test_state() {
+    let old = VehicleState::new();
+    let new = old.clone().with_speed(50).with_fuel(80);
+    assert_eq!(old.change_count(&new), 2);
+    assert_eq!(
+        KomsiCommandKind::try_from(b'y'),
+        Ok(KomsiCommandKind::Speed)
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn test_build_frame() {
+    let frame = KomsiCommand::build_frame(&[KomsiCommand::Ignition(true), KomsiCommand::Speed(50)]);
+    assert_eq!(frame, b"A1y50\n");
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn test_compare() {
+    use crate::vehicle::VehicleLogger;
+    use alloc::{string::String, vec::Vec};
+    use core::cell::RefCell;
+
+    struct Logger(RefCell<Vec<String>>);
+    impl VehicleLogger for Logger {
+        fn log(&self, msg: String) {
+            self.0.borrow_mut().push(msg);
+        }
+    }
+
+    let old = VehicleState::new();
+    let new = old.clone().with_speed(50);
+    assert_eq!(old.compare(&new, false, None), b"y50\n");
+
+    let logger = Logger(RefCell::new(Vec::new()));
+    assert_eq!(
+        old.compare_commands(&new, false, Some(&logger)),
+        [KomsiCommand::Speed(50)]
+    );
+    assert_eq!(logger.0.borrow().len(), 1);
+}
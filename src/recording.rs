@@ -0,0 +1,176 @@
+use crate::komsi::KomsiError;
+
+/// Compresses a sequence of KOMSI frames by collapsing identical consecutive frames.
+///
+/// Each run is stored as a count byte (1-255), the frame length and the frame bytes.
+/// The length is a little endian base 128 varint: 7 bits per byte, the high bit is set
+/// on all but the last byte. Frames are stored unchanged, so they may end with any
+/// line ending or none and contain any bytes. Runs longer than 255 frames are split.
+pub fn rle_encode(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < frames.len() {
+        let frame = &frames[i];
+        let mut count: u8 = 1;
+        while count < u8::MAX
+            && i + (count as usize) < frames.len()
+            && frames[i + count as usize] == *frame
+        {
+            count += 1;
+        }
+
+        buffer.push(count);
+        let mut len = frame.len();
+        while len >= 0x80 {
+            buffer.push((len as u8 & 0x7f) | 0x80);
+            len >>= 7;
+        }
+        buffer.push(len as u8);
+        buffer.extend_from_slice(frame);
+
+        i += count as usize;
+    }
+    buffer
+}
+
+/// Expands a buffer created by [`rle_encode`] back into the list of frames.
+///
+/// Returns `KomsiError::InvalidValue` if a run has a zero count, an invalid length or is
+/// shorter than its length.
+pub fn rle_decode(data: &[u8]) -> Result<Vec<Vec<u8>>, KomsiError> {
+    let mut frames: Vec<Vec<u8>> = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let count = data[i];
+        if count == 0 {
+            return Err(KomsiError::InvalidValue);
+        }
+        i += 1;
+
+        let mut len: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *data.get(i).ok_or(KomsiError::InvalidValue)?;
+            i += 1;
+            if shift >= usize::BITS {
+                return Err(KomsiError::InvalidValue);
+            }
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+
+        let frame = data
+            .get(i..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or(KomsiError::InvalidValue)?;
+        i += len;
+
+        for _ in 0..count {
+            frames.push(frame.to_vec());
+        }
+    }
+    Ok(frames)
+}
+
+/// Encodes the value stream of a single command (e.g. RPM) as deltas of deltas.
+///
+/// Every entry is the change of the delta between consecutive values. The series starts
+/// at 0 with a delta of 0, so the first entry is the first value itself.
+/// Steady or linearly changing values become long runs of zeros.
+/// All arithmetic wraps, so every `u32` series can be restored by [`delta_decode_series`].
+pub fn delta_encode_series(values: &[u32]) -> Vec<i32> {
+    let mut encoded = Vec::with_capacity(values.len());
+    let mut previous: u32 = 0;
+    let mut previous_delta: i32 = 0;
+    for &value in values {
+        let delta = value.wrapping_sub(previous) as i32;
+        encoded.push(delta.wrapping_sub(previous_delta));
+        previous = value;
+        previous_delta = delta;
+    }
+    encoded
+}
+
+/// Restores a value stream encoded by [`delta_encode_series`].
+pub fn delta_decode_series(encoded: &[i32]) -> Vec<u32> {
+    let mut values = Vec::with_capacity(encoded.len());
+    let mut previous: u32 = 0;
+    let mut delta: i32 = 0;
+    for &delta_delta in encoded {
+        delta = delta.wrapping_add(delta_delta);
+        previous = previous.wrapping_add(delta as u32);
+        values.push(previous);
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rle_roundtrip() {
+        let frames = vec![
+            b"A1y50\n".to_vec(),
+            b"A1y50\n".to_vec(),
+            b"A1y50\n".to_vec(),
+            Vec::new(),
+            Vec::new(),
+            b"y55\n".to_vec(),
+        ];
+
+        let encoded = rle_encode(&frames);
+        assert_eq!(encoded, b"\x03\x06A1y50\n\x02\x00\x01\x04y55\n");
+
+        let decoded = rle_decode(&encoded).unwrap();
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn test_rle_long_run() {
+        let frames = vec![b"y1\n".to_vec(); 300];
+        let encoded = rle_encode(&frames);
+        assert_eq!(encoded, b"\xff\x03y1\n\x2d\x03y1\n");
+        assert_eq!(rle_decode(&encoded).unwrap(), frames);
+    }
+
+    #[test]
+    fn test_rle_any_frame_bytes() {
+        let frames = vec![
+            b"A1y50\r".to_vec(),
+            b"A1y50\r".to_vec(),
+            b"y50".to_vec(),
+            vec![b'y', 10, 0, b'A', 1, b'\n'],
+            vec![b'O'; 200],
+        ];
+
+        let encoded = rle_encode(&frames);
+        assert_eq!(encoded[..9], *b"\x02\x06A1y50\r\x01");
+        // the length of 200 needs two bytes
+        assert_eq!(
+            encoded[encoded.len() - 203..encoded.len() - 200],
+            [1, 0xc8, 0x01]
+        );
+        assert_eq!(rle_decode(&encoded).unwrap(), frames);
+    }
+
+    #[test]
+    fn test_delta_series() {
+        let rpm = [800, 900, 1000, 1100, 1150, 1150, 0, u32::MAX];
+        let encoded = delta_encode_series(&rpm);
+        assert_eq!(encoded[..6], [800, -700, 0, 0, -50, -50]);
+        assert_eq!(delta_decode_series(&encoded), rpm);
+        assert!(delta_encode_series(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_rle_decode_invalid() {
+        assert!(rle_decode(b"\x00y1\n").is_err()); // zero count
+        assert!(rle_decode(b"\x02\x03y1").is_err()); // frame too short
+        assert!(rle_decode(b"\x02").is_err()); // missing length
+        assert!(rle_decode(b"\x02\x80").is_err()); // unterminated length
+    }
+}
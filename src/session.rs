@@ -0,0 +1,1178 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::time::Duration;
+
+use crate::komsi::{KomsiCommand, KomsiCommandKind, LineEnding, decode_komsi_line};
+use crate::transport::Transport;
+use crate::vehicle::{Indicator, VehicleLogger, VehicleState, field_name};
+
+/// How long sent frames are remembered for [`KomsiSession::command_rate`].
+const RATE_HISTORY: Duration = Duration::from_secs(60);
+
+/// Maximum number of remembered send times for [`KomsiSession::command_rate`].
+const MAX_RATE_ENTRIES: usize = 4096;
+
+/// Steering angles used for the automatic indicator cancel.
+///
+/// Angles are in degrees, negative to the left and positive to the right.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndicatorAutoCancel {
+    /// Steering angle in the indicated direction that counts as a turn.
+    pub turn_angle: f32,
+    /// Steering angle around the center at which the turn is completed.
+    pub center_angle: f32,
+}
+
+impl Default for IndicatorAutoCancel {
+    fn default() -> Self {
+        Self {
+            turn_angle: 90.0,
+            center_angle: 10.0,
+        }
+    }
+}
+
+/// Exponential backoff of the resyncs after errors.
+///
+/// The first resync waits `base`, every further consecutive failure doubles the delay
+/// up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResyncPolicy {
+    /// Delay after the first failure.
+    pub base: Duration,
+    /// Upper bound of the delay.
+    pub max: Duration,
+}
+
+impl Default for ResyncPolicy {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ResyncPolicy {
+    /// Returns the delay before the next resync after the given number of consecutive failures.
+    ///
+    /// Returns 0 if there were no failures.
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 2u32.checked_pow(failures - 1).unwrap_or(u32::MAX);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Tracks the state that was last sent to a KOMSI board.
+///
+/// The first `update` after creation or `reset` sends a full snapshot,
+/// subsequent updates only send the changed fields.
+#[derive(Debug, Clone, Default)]
+pub struct KomsiSession {
+    /// The state that was last sent.
+    last: VehicleState,
+    /// Whether the initial snapshot has already been sent.
+    initialized: bool,
+    /// Fuel level below which the warning lamp is switched on.
+    low_fuel_threshold: Option<u8>,
+    /// Whether a sequence number is appended to each frame.
+    sequence_numbers: bool,
+    /// Whether a timestamp is prepended to each frame.
+    timestamps: bool,
+    /// The sequence number of the next frame.
+    sequence: u32,
+    /// Distance in meters integrated from the speed.
+    trip_distance: f64,
+    /// Settings of the automatic indicator cancel, if enabled.
+    indicator_auto_cancel: Option<IndicatorAutoCancel>,
+    /// The indicator value for which a turn was detected.
+    turn_indicator: Option<u8>,
+    /// The current time as set by [`KomsiSession::set_clock`].
+    clock: Duration,
+    /// Time and number of commands of recently sent frames, oldest first.
+    sent_history: VecDeque<(Duration, usize)>,
+    /// Number of sent changes per command kind, without the snapshots.
+    change_counts: BTreeMap<KomsiCommandKind, u32>,
+    /// The latest state collected by `aggregate` since the last `flush`.
+    pending: Option<VehicleState>,
+    /// The state before the last non-empty frame, if that frame was not a snapshot.
+    previous: Option<VehicleState>,
+    /// The command kind repeated by `tick` and the idle time after which it is repeated.
+    keepalive: Option<(KomsiCommandKind, Duration)>,
+    /// The time of the last non-empty frame.
+    last_sent_at: Duration,
+    /// Backoff of the resyncs after errors, if enabled.
+    resync_policy: Option<ResyncPolicy>,
+    /// Number of consecutive errors.
+    failures: u32,
+    /// The time before which `tick` does not resync.
+    resync_at: Duration,
+    /// The maximum number of frames per second sent by `tick`, if limited.
+    max_frame_rate: Option<u32>,
+    /// The line terminator of all frames.
+    line_ending: LineEnding,
+}
+
+impl KomsiSession {
+    /// Creates a new session that has not sent anything yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the state that was last sent.
+    pub fn last_state(&self) -> &VehicleState {
+        &self.last
+    }
+
+    /// Returns true if the initial snapshot has already been sent.
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    /// Sets the fuel level below which the warning lamp is switched on, or `None` to disable.
+    ///
+    /// When the fuel drops below the threshold `update` sends `WarningLights(true)`,
+    /// when it rises above again the warning lamp is set back to `lights_warning`.
+    /// While the fuel is low the lamp stays on, even if `lights_warning` is switched off.
+    pub fn set_low_fuel_threshold(&mut self, threshold: Option<u8>) {
+        self.low_fuel_threshold = threshold;
+    }
+
+    /// Enables or disables appending a `SequenceNumber` command to each non-empty frame.
+    ///
+    /// The number starts at 0 and is incremented (wrapping) with every frame, so the board
+    /// can detect missed frames.
+    pub fn set_sequence_numbers(&mut self, enabled: bool) {
+        self.sequence_numbers = enabled;
+    }
+
+    /// Enables or disables prepending a `Timestamp` command to each non-empty frame.
+    ///
+    /// The timestamp is the session clock (see [`KomsiSession::set_clock`]) in milliseconds,
+    /// so the board can log the arrival time of each frame.
+    pub fn set_timestamps(&mut self, enabled: bool) {
+        self.timestamps = enabled;
+    }
+
+    /// Sets the line terminator of all frames of this session, [`LineEnding::Lf`] by default.
+    pub fn set_line_ending(&mut self, ending: LineEnding) {
+        self.line_ending = ending;
+    }
+
+    /// Returns the sequence number the next frame will carry.
+    pub fn next_sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// Returns the distance in meters integrated by [`KomsiSession::accumulate_distance`].
+    pub fn trip_distance(&self) -> u64 {
+        self.trip_distance as u64
+    }
+
+    /// Integrates the last sent speed (in km/h) over `dt` into the trip distance.
+    ///
+    /// Returns a `TripDistance` frame with the trip distance in meters whenever the kilometer
+    /// value changes, like `compare` does for `total_distance`, otherwise an empty buffer.
+    /// The board's odometer (`total_distance`) is not touched.
+    pub fn accumulate_distance(&mut self, dt: Duration) -> Vec<u8> {
+        let before = self.trip_distance();
+        self.trip_distance += self.last.speed as f64 / 3.6 * dt.as_secs_f64();
+        let after = self.trip_distance();
+
+        if before / 1000 == after / 1000 {
+            return Vec::new();
+        }
+        let commands = self.finish_frame(vec![KomsiCommand::TripDistance(after)]);
+        KomsiCommand::build_frame_with(&commands, self.line_ending)
+    }
+
+    /// Sets the current time of the session, e.g. the time since the start of the program.
+    ///
+    /// Sent commands are recorded with this time for [`KomsiSession::command_rate`].
+    /// The clock must not go backwards.
+    pub fn set_clock(&mut self, now: Duration) {
+        self.clock = now;
+        self.prune_history();
+    }
+
+    /// Forgets the sent commands older than [`RATE_HISTORY`] and keeps at most
+    /// [`MAX_RATE_ENTRIES`] send times.
+    fn prune_history(&mut self) {
+        while let Some(&(t, _)) = self.sent_history.front() {
+            if self.clock.saturating_sub(t) <= RATE_HISTORY
+                && self.sent_history.len() <= MAX_RATE_ENTRIES
+            {
+                break;
+            }
+            self.sent_history.pop_front();
+        }
+    }
+
+    /// Returns the number of commands per second sent within the last `window`.
+    ///
+    /// Commands sent exactly `window` ago are not counted. Only the last 60 seconds are
+    /// remembered, so longer windows count at most these. Returns 0 for an empty window.
+    ///
+    /// The session has no clock of its own: call [`KomsiSession::set_clock`] before each
+    /// update, otherwise all commands are recorded at the same time and the rate is
+    /// meaningless.
+    pub fn command_rate(&self, window: Duration) -> f32 {
+        if window.is_zero() {
+            return 0.0;
+        }
+        let count: usize = self
+            .sent_history
+            .iter()
+            .filter(|(t, _)| self.clock.saturating_sub(*t) < window)
+            .map(|(_, n)| n)
+            .sum();
+        count as f32 / window.as_secs_f32()
+    }
+
+    /// Returns the name of the `VehicleState` field that was sent most often as a change,
+    /// e.g. `"speed"`, or `None` if no changes were sent yet.
+    ///
+    /// Full snapshots are not counted. On a tie the kind with the lower command byte wins.
+    pub fn most_active_field(&self) -> Option<&'static str> {
+        self.change_counts
+            .iter()
+            .max_by_key(|&(kind, count)| (count, core::cmp::Reverse(kind)))
+            .map(|(&kind, _)| field_name(kind))
+    }
+
+    /// Adds the timestamp and the sequence number to a non-empty frame and records it as sent.
+    fn finish_frame(&mut self, mut commands: Vec<KomsiCommand>) -> Vec<KomsiCommand> {
+        if self.timestamps && !commands.is_empty() {
+            let millis = self.clock.as_millis() as u64;
+            commands.insert(0, KomsiCommand::Timestamp(millis));
+        }
+        if self.sequence_numbers && !commands.is_empty() {
+            commands.push(KomsiCommand::SequenceNumber(self.sequence));
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+        self.record_sent(commands.len());
+        commands
+    }
+
+    /// Records the number of commands sent at the current time.
+    fn record_sent(&mut self, commands: usize) {
+        if commands > 0 {
+            // frames sent at the same time are counted together
+            match self.sent_history.back_mut() {
+                Some((t, n)) if *t == self.clock => *n += commands,
+                _ => self.sent_history.push_back((self.clock, commands)),
+            }
+            self.prune_history();
+            self.last_sent_at = self.clock;
+        }
+    }
+
+    /// Keeps the board awake: if nothing was sent for `idle` (on the clock set by
+    /// [`KomsiSession::set_clock`]), `tick` repeats the last sent value of the given command
+    /// kind, e.g. `Speed`. `None` disables the repeat.
+    ///
+    /// Only command kinds of `VehicleState` fields can be repeated.
+    pub fn set_keepalive(&mut self, keepalive: Option<(KomsiCommandKind, Duration)>) {
+        self.keepalive = keepalive;
+    }
+
+    /// Returns the keepalive frame if it is due, otherwise an empty buffer.
+    fn keepalive_frame(&mut self) -> Vec<u8> {
+        let Some((kind, idle)) = self.keepalive else {
+            return Vec::new();
+        };
+        if !self.initialized || self.clock.saturating_sub(self.last_sent_at) < idle {
+            return Vec::new();
+        }
+        let Some(cmd) = self
+            .last
+            .compare_commands(&self.last, true, None)
+            .into_iter()
+            .find(|cmd| cmd.kind() == kind)
+        else {
+            return Vec::new();
+        };
+        let commands = self.finish_frame(vec![cmd]);
+        KomsiCommand::build_frame_with(&commands, self.line_ending)
+    }
+
+    /// Enables the automatic indicator cancel with the given angles, or disables it with `None`.
+    pub fn set_indicator_auto_cancel(&mut self, settings: Option<IndicatorAutoCancel>) {
+        self.indicator_auto_cancel = settings;
+        self.turn_indicator = None;
+    }
+
+    /// Like `update`, but first cancels the indicator like a real vehicle.
+    ///
+    /// Once the steering angle reached the turn angle in the indicated direction and then
+    /// returns to the center, `state.indicator` is reset to 0 and the off command is sent.
+    /// Both indicators together (3) are never cancelled.
+    pub fn update_steering(
+        &mut self,
+        state: &mut VehicleState,
+        steering_angle: f32,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<u8> {
+        if let Some(settings) = self.indicator_auto_cancel {
+            let direction = match Indicator::try_from(state.indicator) {
+                Ok(Indicator::Left) => -1.0,
+                Ok(Indicator::Right) => 1.0,
+                _ => 0.0,
+            };
+            if direction == 0.0 || self.turn_indicator.is_some_and(|i| i != state.indicator) {
+                self.turn_indicator = None;
+            }
+
+            if direction != 0.0 && steering_angle * direction >= settings.turn_angle {
+                self.turn_indicator = Some(state.indicator);
+            } else if self.turn_indicator.is_some() && steering_angle.abs() <= settings.center_angle
+            {
+                state.indicator = Indicator::Off.into();
+                self.turn_indicator = None;
+            }
+        }
+        self.update(state, logger)
+    }
+
+    /// Forgets the last sent state, so the next `update` sends a full snapshot again.
+    pub fn reset(&mut self) {
+        self.last = VehicleState::default();
+        self.initialized = false;
+        self.previous = None;
+    }
+
+    /// Returns the frame that reverts the changes of the last non-empty frame.
+    ///
+    /// The session itself is not changed, pass the result of a later `update` with the
+    /// reverted state to keep it in sync. Returns an empty buffer if nothing was sent since
+    /// the initial snapshot.
+    pub fn undo_frame(&self) -> Vec<u8> {
+        match &self.previous {
+            Some(previous) => KomsiCommand::build_frame_with(
+                &self.last.compare_commands(previous, false, None),
+                self.line_ending,
+            ),
+            None => Vec::new(),
+        }
+    }
+
+    /// Compares the new state with the last sent state and returns a buffer of KOMSI commands.
+    ///
+    /// The first call sends all fields, as if `compare` was called with `force = true`.
+    pub fn update(&mut self, new: &VehicleState, logger: Option<&dyn VehicleLogger>) -> Vec<u8> {
+        let commands = self.update_commands(new, logger);
+        KomsiCommand::build_frame_with(&commands, self.line_ending)
+    }
+
+    /// Collects a state for the current aggregation window without sending anything.
+    ///
+    /// Only the latest state of the window is kept, so a value that changes and changes back
+    /// within the window (e.g. a flickering lamp) is not sent at all.
+    pub fn aggregate(&mut self, new: &VehicleState) {
+        self.pending = Some(new.clone());
+    }
+
+    /// Ends the aggregation window and compares the latest collected state with the last sent
+    /// state like `update`.
+    ///
+    /// Returns an empty buffer if no state was collected since the last `flush`.
+    pub fn flush(&mut self, logger: Option<&dyn VehicleLogger>) -> Vec<u8> {
+        match self.pending.take() {
+            Some(new) => self.update(&new, logger),
+            None => Vec::new(),
+        }
+    }
+
+    /// Enables the backoff of resyncs after errors with the given policy, or disables it
+    /// with `None`.
+    pub fn set_resync_policy(&mut self, policy: Option<ResyncPolicy>) {
+        self.resync_policy = policy;
+    }
+
+    /// Returns the number of consecutive errors since the last successfully sent frame.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Reports an error of the board or the link.
+    ///
+    /// The session is reset, so the next `tick` sends a full snapshot. With a
+    /// [`ResyncPolicy`] this resync is delayed on the session clock according to the number
+    /// of consecutive errors.
+    pub fn report_error(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+        let delay = self
+            .resync_policy
+            .map_or(Duration::ZERO, |p| p.delay(self.failures));
+        self.resync_at = self.clock + delay;
+        self.reset();
+    }
+
+    /// Limits the frames sent by `tick` to `rate` frames per second on the session clock,
+    /// or removes the limit with `None`.
+    ///
+    /// Changes are never dropped: while the limit is exceeded `tick` holds them back, and
+    /// the next allowed `tick` sends the difference between the last sent state and the
+    /// latest one.
+    pub fn set_max_frame_rate(&mut self, rate: Option<u32>) {
+        self.max_frame_rate = rate;
+    }
+
+    /// Returns true if the next frame would exceed the maximum frame rate.
+    fn throttled(&self) -> bool {
+        let Some(rate) = self.max_frame_rate else {
+            return false;
+        };
+        let interval = Duration::from_secs(1) / rate.max(1);
+        self.initialized && self.clock.saturating_sub(self.last_sent_at) < interval
+    }
+
+    /// Compares the new state with the last sent state and sends the changes over the transport.
+    ///
+    /// Nothing is sent if nothing changed, except the keepalive (see
+    /// [`KomsiSession::set_keepalive`]), or if the frame rate is limited (see
+    /// [`KomsiSession::set_max_frame_rate`]). If sending fails the error is reported like
+    /// [`KomsiSession::report_error`], so a later `tick` sends a full snapshot again;
+    /// until the resync delay has passed `tick` sends nothing.
+    pub fn tick<T: Transport + ?Sized>(
+        &mut self,
+        new: &VehicleState,
+        transport: &mut T,
+    ) -> io::Result<()> {
+        if (!self.initialized && self.clock < self.resync_at) || self.throttled() {
+            return Ok(());
+        }
+        let mut frame = self.update(new, None);
+        if frame.is_empty() {
+            frame = self.keepalive_frame();
+        }
+        if frame.is_empty() {
+            return Ok(());
+        }
+        let result = transport.send_frame(&frame);
+        match result {
+            Ok(()) => self.failures = 0,
+            Err(_) => self.report_error(),
+        }
+        result
+    }
+
+    /// Compares the new state with the last sent state and returns the list of KOMSI commands.
+    fn update_commands(
+        &mut self,
+        new: &VehicleState,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<KomsiCommand> {
+        let commands = match self.low_fuel_threshold {
+            // compare the displayed lamp, so the lamp stays on while the fuel is low
+            Some(threshold) => {
+                let mut old = self.last.clone();
+                let mut shown = new.clone();
+                old.lights_warning |= old.fuel < threshold;
+                shown.lights_warning |= shown.fuel < threshold;
+                old.compare_commands(&shown, !self.initialized, logger)
+            }
+            None => self.last.compare_commands(new, !self.initialized, logger),
+        };
+
+        if self.initialized {
+            for cmd in &commands {
+                *self.change_counts.entry(cmd.kind()).or_default() += 1;
+            }
+        }
+
+        let commands = self.finish_frame(commands);
+        if self.initialized && !commands.is_empty() {
+            self.previous = Some(self.last.clone());
+        }
+        self.last = new.clone();
+        self.initialized = true;
+        commands
+    }
+
+    /// Like `update`, but sends the speed extrapolated `lookahead` into the future
+    /// instead of `new.speed`, to compensate for the latency of the link.
+    ///
+    /// `samples` are `(timestamp, speed)` pairs in ascending time order, see [`predict_speed`].
+    /// Falls back to `new.speed` if there are not enough samples.
+    pub fn update_predicted(
+        &mut self,
+        new: &VehicleState,
+        samples: &[(Duration, u32)],
+        lookahead: Duration,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<u8> {
+        let mut predicted = new.clone();
+        if let Some(speed) = predict_speed(samples, lookahead) {
+            predicted.speed = speed;
+        }
+        self.update(&predicted, logger)
+    }
+}
+
+/// A gap in the sequence numbers of received frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceGap {
+    /// The sequence number that was expected.
+    pub expected: u32,
+    /// The sequence number that was received instead.
+    pub received: u32,
+}
+
+impl SequenceGap {
+    /// Returns the number of frames missing in this gap.
+    pub fn missing(&self) -> u32 {
+        self.received.wrapping_sub(self.expected)
+    }
+}
+
+/// Returns the value of the `SequenceNumber` command in a frame, if any.
+///
+/// The frame is decoded with [`decode_komsi_line`], so a frame that can not be decoded
+/// (e.g. with binary encoded values) has no sequence number.
+pub fn frame_sequence_number(frame: &[u8]) -> Option<u32> {
+    decode_komsi_line(frame)
+        .ok()?
+        .into_iter()
+        .rev()
+        .find_map(|cmd| match cmd {
+            KomsiCommand::SequenceNumber(n) => Some(n),
+            _ => None,
+        })
+}
+
+/// Checks the sequence numbers of consecutively received frames and returns all gaps.
+///
+/// Frames without a sequence number are skipped. Numbers wrap around like in
+/// [`KomsiSession::set_sequence_numbers`].
+///
+/// Only forward jumps of less than half the number range are gaps. A duplicate or a
+/// frame received out of order has a number behind the expected one; it is no gap and
+/// the expected number stays the same.
+pub fn find_sequence_gaps<F: AsRef<[u8]>>(frames: &[F]) -> Vec<SequenceGap> {
+    let mut gaps = Vec::new();
+    let mut expected: Option<u32> = None;
+    for n in frames
+        .iter()
+        .filter_map(|f| frame_sequence_number(f.as_ref()))
+    {
+        if let Some(e) = expected {
+            let ahead = n.wrapping_sub(e);
+            if ahead >= u32::MAX / 2 {
+                // duplicate or late frame
+                continue;
+            }
+            if ahead > 0 {
+                gaps.push(SequenceGap {
+                    expected: e,
+                    received: n,
+                });
+            }
+        }
+        expected = Some(n.wrapping_add(1));
+    }
+    gaps
+}
+
+/// Extrapolates the speed `lookahead` into the future from the last two samples.
+///
+/// `samples` are `(timestamp, speed)` pairs in ascending time order. The current acceleration
+/// is taken from the last two samples and the result is rounded and clamped at 0.
+/// Returns `None` if there are less than two samples or their timestamps are equal.
+pub fn predict_speed(samples: &[(Duration, u32)], lookahead: Duration) -> Option<u32> {
+    let [.., (t0, v0), (t1, v1)] = samples else {
+        return None;
+    };
+    let dt = t1.checked_sub(*t0)?.as_secs_f64();
+    if dt == 0.0 {
+        return None;
+    }
+    let acceleration = (*v1 as f64 - *v0 as f64) / dt;
+    let predicted = *v1 as f64 + acceleration * lookahead.as_secs_f64();
+    // the float to int cast saturates, negative speeds become 0
+    Some(predicted.round() as u32)
+}
+
+/// Tracks the last sent value of each command kind independently of a `VehicleState`.
+#[derive(Debug, Clone, Default)]
+pub struct CommandCache {
+    /// The last sent command per kind.
+    sent: BTreeMap<KomsiCommandKind, KomsiCommand>,
+}
+
+impl CommandCache {
+    /// Creates a new empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the last sent command of the given kind, if any.
+    pub fn get(&self, kind: KomsiCommandKind) -> Option<&KomsiCommand> {
+        self.sent.get(&kind)
+    }
+
+    /// Forgets all sent values, so every command is sent again.
+    pub fn clear(&mut self) {
+        self.sent.clear();
+    }
+
+    /// Records the command and returns its KOMSI command buffer if the value differs
+    /// from the last sent value of the same kind.
+    ///
+    /// Returns `None` if the command was already sent with the same value.
+    pub fn update(&mut self, cmd: KomsiCommand) -> Option<Vec<u8>> {
+        if self.sent.get(&cmd.kind()) == Some(&cmd) {
+            return None;
+        }
+        self.sent.insert(cmd.kind(), cmd);
+        Some(cmd.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_update_is_snapshot() {
+        let mut session = KomsiSession::new();
+        assert!(!session.is_initialized());
+
+        let state = VehicleState::new();
+        let buffer = session.update(&state, None);
+        assert_eq!(buffer, state.compare(&state, true, None));
+        assert!(session.is_initialized());
+
+        // nothing changed since the snapshot
+        assert!(session.update(&state, None).is_empty());
+
+        let mut new = state.clone();
+        new.speed = 50;
+        assert_eq!(session.update(&new, None), b"y50\n");
+        assert_eq!(session.last_state().speed, 50);
+    }
+
+    #[test]
+    fn test_reset_forces_snapshot() {
+        let mut session = KomsiSession::new();
+        let state = VehicleState::new();
+        session.update(&state, None);
+
+        session.reset();
+        assert!(!session.is_initialized());
+        assert!(!session.update(&state, None).is_empty());
+    }
+
+    #[test]
+    fn test_command_rate() {
+        let mut session = KomsiSession::new();
+        let mut state = VehicleState::new();
+        session.update(&state, None); // snapshot
+        let snapshot = state.compare_commands(&state, true, None).len();
+        assert_eq!(
+            session.command_rate(Duration::from_secs(1)),
+            snapshot as f32
+        );
+
+        for i in 1..=10 {
+            session.set_clock(Duration::from_millis(100 * i));
+            state.speed = i as u32;
+            session.update(&state, None);
+        }
+        // the snapshot at 0 s is outside of the window
+        assert_eq!(session.command_rate(Duration::from_secs(1)), 10.0);
+        assert_eq!(session.command_rate(Duration::from_millis(500)), 10.0);
+        assert_eq!(session.command_rate(Duration::ZERO), 0.0);
+
+        // empty frames are not counted
+        session.set_clock(Duration::from_secs(2));
+        session.update(&state, None);
+        assert_eq!(session.command_rate(Duration::from_secs(1)), 0.0);
+
+        // old frames are forgotten
+        session.set_clock(Duration::from_secs(100));
+        assert_eq!(session.command_rate(Duration::from_secs(100)), 0.0);
+    }
+
+    #[test]
+    fn test_rate_history_is_bounded() {
+        // without set_clock all frames are sent at the same time
+        let mut session = KomsiSession::new();
+        let mut state = VehicleState::new();
+        for i in 0..10_000 {
+            state.speed = i;
+            session.update(&state, None);
+        }
+        assert_eq!(session.sent_history.len(), 1);
+
+        // with a fine clock the number of send times is capped
+        for i in 0..10_000 {
+            session.set_clock(Duration::from_micros(i));
+            state.speed = i as u32;
+            session.update(&state, None);
+        }
+        assert_eq!(session.sent_history.len(), MAX_RATE_ENTRIES);
+    }
+
+    struct FailingTransport;
+
+    impl Transport for FailingTransport {
+        fn send_frame(&mut self, _frame: &[u8]) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+    }
+
+    #[test]
+    fn test_tick() {
+        let mut session = KomsiSession::new();
+        let mut state = VehicleState::new();
+        let mut sent: Vec<u8> = Vec::new();
+        session.tick(&state, &mut sent).unwrap();
+        assert_eq!(sent, state.compare(&state, true, None));
+
+        sent.clear();
+        session.tick(&state, &mut sent).unwrap();
+        assert!(sent.is_empty());
+
+        state.speed = 30;
+        session.tick(&state, &mut sent).unwrap();
+        assert_eq!(sent, b"y30\n");
+
+        // a failed send forces a new snapshot
+        state.speed = 40;
+        assert!(session.tick(&state, &mut FailingTransport).is_err());
+        assert!(!session.is_initialized());
+    }
+
+    #[test]
+    fn test_max_frame_rate() {
+        let mut session = KomsiSession::new();
+        session.set_max_frame_rate(Some(10));
+        let mut state = VehicleState::new();
+        let mut sent: Vec<u8> = Vec::new();
+        session.tick(&state, &mut sent).unwrap();
+
+        // both changes within 100 ms are held back and sent together
+        sent.clear();
+        state.speed = 30;
+        session.set_clock(Duration::from_millis(50));
+        session.tick(&state, &mut sent).unwrap();
+        state.speed = 35;
+        state.fuel = 5;
+        session.set_clock(Duration::from_millis(80));
+        session.tick(&state, &mut sent).unwrap();
+        assert!(sent.is_empty());
+
+        session.set_clock(Duration::from_millis(100));
+        session.tick(&state, &mut sent).unwrap();
+        assert_eq!(sent, VehicleState::new().compare(&state, false, None));
+
+        sent.clear();
+        state.speed = 40;
+        session.set_clock(Duration::from_millis(150));
+        session.tick(&state, &mut sent).unwrap();
+        assert!(sent.is_empty());
+        session.set_max_frame_rate(None);
+        session.tick(&state, &mut sent).unwrap();
+        assert_eq!(sent, b"y40\n");
+    }
+
+    #[test]
+    fn test_most_active_field() {
+        let mut session = KomsiSession::new();
+        let mut state = VehicleState::new();
+        session.update(&state, None);
+        assert_eq!(session.most_active_field(), None);
+
+        for i in 1..=3 {
+            state.fuel = i;
+            state.speed = i as u32 * 10;
+            session.update(&state, None);
+        }
+        // tie between fuel (x) and speed (y)
+        assert_eq!(session.most_active_field(), Some("fuel"));
+
+        state.speed = 5;
+        session.update(&state, None);
+        assert_eq!(session.most_active_field(), Some("speed"));
+
+        for i in 1..=5 {
+            state.total_distance = i * 1000;
+            state.total_distance_km = i;
+            session.update(&state, None);
+        }
+        assert_eq!(session.most_active_field(), Some("total_distance"));
+    }
+
+    #[test]
+    fn test_aggregate_flush() {
+        let mut session = KomsiSession::new();
+        let mut state = VehicleState::new();
+        state.lights_main = true;
+        session.update(&state, None);
+
+        // on -> off -> on within one window is no net change
+        state.lights_main = false;
+        session.aggregate(&state);
+        state.lights_main = true;
+        session.aggregate(&state);
+        assert!(session.flush(None).is_empty());
+
+        state.speed = 10;
+        session.aggregate(&state);
+        state.speed = 20;
+        session.aggregate(&state);
+        assert_eq!(session.flush(None), b"y20\n");
+        assert!(session.flush(None).is_empty());
+    }
+
+    #[test]
+    fn test_undo_frame() {
+        let mut session = KomsiSession::new();
+        let mut state = VehicleState::new();
+        session.update(&state, None);
+        assert!(session.undo_frame().is_empty());
+
+        state.speed = 30;
+        state.lights_main = true;
+        session.update(&state, None);
+        // empty frames do not change what is undone
+        session.update(&state, None);
+        assert_eq!(session.undo_frame(), b"G0y0\n");
+
+        state.speed = 40;
+        session.update(&state, None);
+        assert_eq!(session.undo_frame(), b"y30\n");
+
+        session.reset();
+        assert!(session.undo_frame().is_empty());
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let mut session = KomsiSession::new();
+        session.set_keepalive(Some((KomsiCommandKind::Speed, Duration::from_secs(2))));
+        let mut state = VehicleState::new();
+        state.speed = 12;
+        let mut sent: Vec<u8> = Vec::new();
+        session.tick(&state, &mut sent).unwrap();
+
+        sent.clear();
+        session.set_clock(Duration::from_secs(1));
+        session.tick(&state, &mut sent).unwrap();
+        assert!(sent.is_empty());
+
+        session.set_clock(Duration::from_secs(2));
+        session.tick(&state, &mut sent).unwrap();
+        assert_eq!(sent, b"y12\n");
+
+        // the repeat restarts the idle time, like every other frame
+        sent.clear();
+        session.set_clock(Duration::from_secs(3));
+        session.tick(&state, &mut sent).unwrap();
+        state.fuel = 5;
+        session.set_clock(Duration::from_secs(4));
+        session.tick(&state, &mut sent).unwrap();
+        session.set_clock(Duration::from_secs(5));
+        session.tick(&state, &mut sent).unwrap();
+        assert_eq!(sent, b"x5\n");
+    }
+
+    #[test]
+    fn test_resync_policy() {
+        let policy = ResyncPolicy {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        assert_eq!(policy.delay(0), Duration::ZERO);
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+
+        let mut session = KomsiSession::new();
+        session.set_resync_policy(Some(policy));
+        let state = VehicleState::new();
+        assert!(session.tick(&state, &mut FailingTransport).is_err());
+        session.set_clock(Duration::from_millis(100));
+        assert!(session.tick(&state, &mut FailingTransport).is_err());
+        assert_eq!(session.consecutive_failures(), 2);
+
+        // no resync before the backoff of 200 ms has passed
+        let mut sent: Vec<u8> = Vec::new();
+        session.set_clock(Duration::from_millis(250));
+        session.tick(&state, &mut sent).unwrap();
+        assert!(sent.is_empty());
+
+        session.set_clock(Duration::from_millis(300));
+        session.tick(&state, &mut sent).unwrap();
+        assert_eq!(sent, state.compare(&state, true, None));
+        assert_eq!(session.consecutive_failures(), 0);
+    }
+
+    #[test]
+    fn test_command_cache() {
+        let mut cache = CommandCache::new();
+        assert_eq!(cache.update(KomsiCommand::Speed(50)), Some(b"y50".to_vec()));
+        assert_eq!(cache.update(KomsiCommand::Speed(50)), None);
+        assert_eq!(cache.update(KomsiCommand::Fuel(50)), Some(b"x50".to_vec()));
+        assert_eq!(cache.update(KomsiCommand::Speed(55)), Some(b"y55".to_vec()));
+        assert_eq!(
+            cache.get(KomsiCommandKind::Speed),
+            Some(&KomsiCommand::Speed(55))
+        );
+
+        cache.clear();
+        assert!(cache.update(KomsiCommand::Speed(55)).is_some());
+    }
+
+    #[test]
+    fn test_predict_speed() {
+        let ms = Duration::from_millis;
+        let accelerating = [(ms(0), 40), (ms(100), 48), (ms(200), 50)];
+        assert_eq!(predict_speed(&accelerating, ms(100)), Some(52));
+        assert_eq!(predict_speed(&accelerating, ms(0)), Some(50));
+
+        let braking = [(ms(0), 10), (ms(100), 2)];
+        assert_eq!(predict_speed(&braking, ms(500)), Some(0));
+
+        assert_eq!(predict_speed(&[(ms(0), 10)], ms(100)), None);
+        assert_eq!(predict_speed(&[(ms(5), 10), (ms(5), 20)], ms(100)), None);
+    }
+
+    #[test]
+    fn test_update_predicted() {
+        let mut session = KomsiSession::new();
+        let mut state = VehicleState::new();
+        session.update(&state, None);
+
+        state.speed = 50;
+        let samples = [(Duration::ZERO, 40), (Duration::from_secs(1), 50)];
+        let buffer = session.update_predicted(&state, &samples, Duration::from_millis(500), None);
+        assert_eq!(buffer, b"y55\n");
+    }
+
+    #[test]
+    fn test_low_fuel_warning() {
+        let mut session = KomsiSession::new();
+        session.set_low_fuel_threshold(Some(10));
+
+        let mut state = VehicleState::new();
+        state.fuel = 12;
+        session.update(&state, None);
+
+        state.fuel = 11;
+        assert_eq!(session.update(&state, None), b"x11\n");
+
+        // downward crossing switches the warning lamp on
+        state.fuel = 9;
+        assert_eq!(session.update(&state, None), b"F1x9\n");
+
+        state.fuel = 8;
+        assert_eq!(session.update(&state, None), b"x8\n");
+
+        // the lamp stays on while the fuel is low
+        state.lights_warning = true;
+        assert!(session.update(&state, None).is_empty());
+        state.lights_warning = false;
+        assert!(session.update(&state, None).is_empty());
+
+        // upward crossing restores the warning lamp
+        state.fuel = 50;
+        assert_eq!(session.update(&state, None), b"F0x50\n");
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let mut session = KomsiSession::new();
+        session.set_sequence_numbers(true);
+
+        let mut state = VehicleState::new();
+        let snapshot = session.update(&state, None);
+        assert!(snapshot.ends_with(b"Y0\n"));
+
+        // empty frames are not numbered
+        assert!(session.update(&state, None).is_empty());
+
+        state.speed = 10;
+        assert_eq!(session.update(&state, None), b"y10Y1\n");
+        assert_eq!(session.next_sequence(), 2);
+
+        session.set_line_ending(LineEnding::CrLf);
+        state.speed = 20;
+        assert_eq!(session.update(&state, None), b"y20Y2\r\n");
+        session.set_line_ending(LineEnding::Lf);
+
+        // keepalive and trip distance frames are numbered, too
+        session.set_keepalive(Some((KomsiCommandKind::Speed, Duration::from_secs(1))));
+        session.set_clock(Duration::from_secs(1));
+        let mut sent: Vec<u8> = Vec::new();
+        session.tick(&state, &mut sent).unwrap();
+        assert_eq!(sent, b"y20Y3\n");
+        state.speed = 72;
+        session.update(&state, None);
+        assert_eq!(
+            session.accumulate_distance(Duration::from_secs(60)),
+            b"k1200Y5\n"
+        );
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let mut session = KomsiSession::new();
+        session.set_timestamps(true);
+        session.set_sequence_numbers(true);
+
+        let mut state = VehicleState::new();
+        session.update(&state, None);
+        assert!(session.update(&state, None).is_empty());
+
+        session.set_clock(Duration::from_millis(1500));
+        state.speed = 10;
+        assert_eq!(session.update(&state, None), b"c1500y10Y1\n");
+
+        // the keepalive frame carries a timestamp, too
+        session.set_keepalive(Some((KomsiCommandKind::Speed, Duration::from_secs(1))));
+        session.set_clock(Duration::from_millis(2500));
+        let mut sent: Vec<u8> = Vec::new();
+        session.tick(&state, &mut sent).unwrap();
+        assert_eq!(sent, b"c2500y10Y2\n");
+    }
+
+    #[test]
+    fn test_find_sequence_gaps() {
+        assert_eq!(frame_sequence_number(b"y10Y42\n"), Some(42));
+        assert_eq!(frame_sequence_number(b"y10\n"), None);
+        // a checksum or value digits that equal 'Y' are not a sequence number
+        assert_eq!(frame_sequence_number(b"y10j89Y3\r\n"), Some(3));
+        assert_eq!(frame_sequence_number(&[b'y', 0x59, b'5', b'\n']), None);
+
+        let frames: [&[u8]; 6] = [
+            b"y1Y7\n", b"y2Y8\n", b"A1\n", b"y3Y11\n", b"Y12\n", b"Y14\n",
+        ];
+        let gaps = find_sequence_gaps(&frames);
+        assert_eq!(
+            gaps,
+            [
+                SequenceGap {
+                    expected: 9,
+                    received: 11
+                },
+                SequenceGap {
+                    expected: 13,
+                    received: 14
+                },
+            ]
+        );
+        assert_eq!(gaps.iter().map(|g| g.missing()).sum::<u32>(), 3);
+
+        // wrap around is not a gap
+        let frames: [&[u8]; 2] = [b"Y4294967295\n", b"Y0\n"];
+        assert!(find_sequence_gaps(&frames).is_empty());
+        let frames: [&[u8]; 2] = [b"Y4294967294\n", b"Y1\n"];
+        let gaps = find_sequence_gaps(&frames);
+        assert_eq!(
+            gaps,
+            [SequenceGap {
+                expected: 4294967295,
+                received: 1
+            }]
+        );
+        assert_eq!(gaps[0].missing(), 2);
+    }
+
+    #[test]
+    fn test_find_sequence_gaps_backwards() {
+        // a duplicate is no gap
+        let frames: [&[u8]; 3] = [b"Y5\n", b"Y5\n", b"Y6\n"];
+        assert!(find_sequence_gaps(&frames).is_empty());
+
+        // a late frame is no gap and does not move the expected number back
+        let frames: [&[u8]; 4] = [b"Y5\n", b"Y7\n", b"Y6\n", b"Y8\n"];
+        assert_eq!(
+            find_sequence_gaps(&frames),
+            [SequenceGap {
+                expected: 6,
+                received: 7
+            }]
+        );
+
+        // a late frame before the wrap around
+        let frames: [&[u8]; 3] = [b"Y0\n", b"Y4294967295\n", b"Y1\n"];
+        assert!(find_sequence_gaps(&frames).is_empty());
+    }
+
+    #[test]
+    fn test_accumulate_distance() {
+        let mut session = KomsiSession::new();
+        let mut state = VehicleState::new();
+        state.speed = 72; // 20 m/s
+        session.update(&state, None);
+
+        assert!(
+            session
+                .accumulate_distance(Duration::from_secs(30))
+                .is_empty()
+        );
+        assert_eq!(session.trip_distance(), 600);
+
+        let buffer = session.accumulate_distance(Duration::from_secs(25));
+        assert_eq!(buffer, b"k1100\n");
+        assert_eq!(session.trip_distance(), 1100);
+    }
+
+    #[test]
+    fn test_accumulate_distance_keeps_odometer() {
+        let mut session = KomsiSession::new();
+        let mut state = VehicleState::new();
+        state.speed = 72;
+        state.total_distance = 123_456;
+        state.total_distance_km = 123;
+        let snapshot = session.update(&state, None);
+        let odometer = |frame: &[u8]| {
+            decode_komsi_line(frame)
+                .unwrap()
+                .into_iter()
+                .filter(|cmd| cmd.kind() == KomsiCommandKind::Odometer)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(odometer(&snapshot), [KomsiCommand::Odometer(123_456)]);
+
+        let trip = session.accumulate_distance(Duration::from_secs(60));
+        assert_eq!(trip, b"k1200\n");
+        assert!(odometer(&trip).is_empty());
+
+        // the next compare has nothing to restore
+        assert!(session.update(&state, None).is_empty());
+    }
+
+    #[test]
+    fn test_indicator_auto_cancel() {
+        let mut session = KomsiSession::new();
+        session.set_indicator_auto_cancel(Some(IndicatorAutoCancel::default()));
+
+        let mut state = VehicleState::new();
+        session.update(&state, None);
+
+        state.indicator = 2;
+        assert_eq!(session.update_steering(&mut state, 5.0, None), b"D2\n");
+        // steering to the left does not count for the right indicator
+        assert!(session.update_steering(&mut state, -120.0, None).is_empty());
+        assert!(session.update_steering(&mut state, 0.0, None).is_empty());
+        assert_eq!(state.indicator, 2);
+
+        assert!(session.update_steering(&mut state, 180.0, None).is_empty());
+        assert!(session.update_steering(&mut state, 40.0, None).is_empty());
+        assert_eq!(session.update_steering(&mut state, 3.0, None), b"D0\n");
+        assert_eq!(state.indicator, 0);
+
+        // hazard lights are not cancelled
+        state.indicator = 3;
+        session.update_steering(&mut state, 180.0, None);
+        session.update_steering(&mut state, 0.0, None);
+        assert_eq!(state.indicator, 3);
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+/// A link to a KOMSI board, e.g. a serial port, a TCP connection or a mock.
+pub trait Transport {
+    /// Sends a complete frame to the board.
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+}
+
+/// Collects all sent frames, e.g. for tests.
+impl Transport for Vec<u8> {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.extend_from_slice(frame);
+        Ok(())
+    }
+}
+
+/// Sends every frame as a single UDP datagram to a fixed address, e.g. a networked display.
+#[derive(Debug)]
+pub struct UdpSink {
+    /// The local socket the datagrams are sent from.
+    socket: UdpSocket,
+    /// The address of the board.
+    target: SocketAddr,
+}
+
+impl UdpSink {
+    /// Binds a local socket to `local` (e.g. `"0.0.0.0:0"`) and sends to `target`.
+    ///
+    /// If `target` resolves to several addresses the first one is used.
+    pub fn new<L: ToSocketAddrs, T: ToSocketAddrs>(local: L, target: T) -> io::Result<Self> {
+        let target = target
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no target address"))?;
+        Ok(Self {
+            socket: UdpSocket::bind(local)?,
+            target,
+        })
+    }
+
+    /// Returns the address the frames are sent to.
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+}
+
+impl Transport for UdpSink {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let sent = self.socket.send_to(frame, self.target)?;
+        if sent != frame.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "frame was truncated",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by [`FrameRing::try_push`] if the ring is full.
+///
+/// Contains the rejected frame, so it can be retried later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Full(pub Vec<u8>);
+
+/// A fixed-capacity queue of frames waiting to be sent.
+///
+/// When the ring is full new frames are rejected, so the sender can apply backpressure
+/// instead of growing memory while the link is stalled.
+#[derive(Debug, Clone)]
+pub struct FrameRing {
+    /// The queued frames, oldest first.
+    frames: VecDeque<Vec<u8>>,
+    /// Maximum number of queued frames.
+    capacity: usize,
+}
+
+impl FrameRing {
+    /// Creates a new ring that holds up to `capacity` frames.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Returns the maximum number of queued frames.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of queued frames.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns true if no frames are queued.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Returns true if no more frames can be queued.
+    pub fn is_full(&self) -> bool {
+        self.frames.len() >= self.capacity
+    }
+
+    /// Queues a frame for sending.
+    ///
+    /// Empty frames (no changes) are accepted without taking up space.
+    /// Returns the frame in [`Full`] if the ring is full.
+    pub fn try_push(&mut self, frame: Vec<u8>) -> Result<(), Full> {
+        if frame.is_empty() {
+            return Ok(());
+        }
+        if self.is_full() {
+            return Err(Full(frame));
+        }
+        self.frames.push_back(frame);
+        Ok(())
+    }
+
+    /// Removes and returns the oldest queued frame.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_ring_backpressure() {
+        let mut ring = FrameRing::new(2);
+        assert!(ring.is_empty());
+
+        assert_eq!(ring.try_push(b"y1\n".to_vec()), Ok(()));
+        assert_eq!(ring.try_push(Vec::new()), Ok(())); // ignored
+        assert_eq!(ring.try_push(b"y2\n".to_vec()), Ok(()));
+        assert!(ring.is_full());
+        assert_eq!(ring.try_push(b"y3\n".to_vec()), Err(Full(b"y3\n".to_vec())));
+
+        assert_eq!(ring.pop(), Some(b"y1\n".to_vec()));
+        assert_eq!(ring.try_push(b"y3\n".to_vec()), Ok(()));
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.pop(), Some(b"y2\n".to_vec()));
+        assert_eq!(ring.pop(), Some(b"y3\n".to_vec()));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn test_udp_sink() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sink = UdpSink::new("127.0.0.1:0", receiver.local_addr().unwrap()).unwrap();
+        assert_eq!(sink.target(), receiver.local_addr().unwrap());
+
+        sink.send_frame(b"y50\n").unwrap();
+        let mut buf = [0u8; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"y50\n");
+    }
+}
@@ -1,17 +1,279 @@
-use crate::komsi::KomsiCommand;
+#[cfg(feature = "std")]
+use crate::komsi::decode_komsi_line;
+#[cfg(feature = "alloc")]
+use crate::komsi::sort_by_dependencies;
+use crate::komsi::{CommandGroup, Framing, KomsiCommand, KomsiCommandKind, KomsiError, LineEnding};
+#[cfg(feature = "alloc")]
+use alloc::{format, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
 
 /// Trait for logging state changes.
-#[cfg(feature = "std")]
+#[cfg(feature = "alloc")]
 pub trait VehicleLogger {
     /// Logs a message.
     fn log(&self, msg: String);
 }
 
+/// How sensor gauges are sent while the engine is off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EngineOffSensors {
+    /// Sensor values are sent unchanged.
+    #[default]
+    Send,
+    /// Sensor values are sent as 0 while the engine is off.
+    Zero,
+    /// Sensor commands are not sent while the engine is off.
+    Suppress,
+}
+
+/// The position of the turn indicator, as sent in the `indicator` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Indicator {
+    /// No indicator.
+    #[default]
+    Off = 0,
+    /// Left indicator.
+    Left = 1,
+    /// Right indicator.
+    Right = 2,
+    /// Both indicators, e.g. as hazard lights.
+    Both = 3,
+}
+
+impl From<Indicator> for u8 {
+    fn from(indicator: Indicator) -> u8 {
+        indicator as u8
+    }
+}
+
+impl TryFrom<u8> for Indicator {
+    type Error = KomsiError;
+
+    /// Returns `KomsiError::InvalidValue` for values above 3.
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Indicator::Off),
+            1 => Ok(Indicator::Left),
+            2 => Ok(Indicator::Right),
+            3 => Ok(Indicator::Both),
+            _ => Err(KomsiError::InvalidValue),
+        }
+    }
+}
+
+/// The position of the gear selector, as sent in the `gear_selector` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum GearSelector {
+    /// Neutral gear, see [`GEAR_NEUTRAL`].
+    #[default]
+    Neutral = GEAR_NEUTRAL,
+    /// Drive.
+    Drive = 1,
+    /// Reverse gear.
+    Reverse = 2,
+    /// Park.
+    Park = 3,
+}
+
+impl From<GearSelector> for u8 {
+    fn from(gear: GearSelector) -> u8 {
+        gear as u8
+    }
+}
+
+impl TryFrom<u8> for GearSelector {
+    type Error = KomsiError;
+
+    /// Returns `KomsiError::InvalidValue` for values above 3.
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            GEAR_NEUTRAL => Ok(GearSelector::Neutral),
+            1 => Ok(GearSelector::Drive),
+            2 => Ok(GearSelector::Reverse),
+            3 => Ok(GearSelector::Park),
+            _ => Err(KomsiError::InvalidValue),
+        }
+    }
+}
+
+/// Common speed limit sign values.
+pub const SPEED_LIMIT_SIGNS: &[u32] = &[10, 20, 30, 40, 50, 60, 70, 80, 100, 120];
+
+/// A common water temperature at which the engine overheats,
+/// see [`CompareOptions::overheat_warning`].
+pub const WATER_OVERHEAT: u32 = 110;
+
+/// The `gear_selector` position of the neutral gear, see [`CompareOptions::neutral_lamp`].
+pub const GEAR_NEUTRAL: u8 = 0;
+
+/// Returns the sign value nearest to `value`.
+///
+/// On a tie the lower sign is chosen. Returns `value` unchanged if `signs` is empty.
+pub fn nearest_speed_limit_sign(value: u32, signs: &[u32]) -> u32 {
+    signs
+        .iter()
+        .copied()
+        .min_by_key(|&sign| (sign.abs_diff(value), sign))
+        .unwrap_or(value)
+}
+
+/// Returns the frames a sender has to emit for a timeline of states, e.g. as reference output
+/// for integration tests.
+///
+/// The first state is sent as a full snapshot, every later state as the changes against the
+/// state before, like [`KomsiSession`](crate::KomsiSession) does. States without changes
+/// produce no frame. Each frame is returned with the time of its state.
+#[cfg(feature = "std")]
+pub fn expected_frames(
+    timeline: &[(core::time::Duration, VehicleState)],
+) -> Vec<(core::time::Duration, Vec<u8>)> {
+    let mut frames = Vec::new();
+    let mut last: Option<&VehicleState> = None;
+    for (time, state) in timeline {
+        let frame = match last {
+            Some(last) => last.compare(state, false, None),
+            None => state.compare(state, true, None),
+        };
+        if !frame.is_empty() {
+            frames.push((*time, frame));
+        }
+        last = Some(state);
+    }
+    frames
+}
+
+/// The highest retarder stage, see [`VehicleState::retarder`].
+pub const MAX_RETARDER: u8 = 5;
+
+/// Options for [`VehicleState::compare_with_options`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompareOptions {
+    /// Include all fields regardless of changes.
+    pub force: bool,
+    /// Handling of the sensor group commands (rpm, pressure, ...) while the engine is off.
+    pub engine_off_sensors: EngineOffSensors,
+    /// If set, `maxspeed` is sent as the nearest of these speed limit sign values,
+    /// e.g. [`SPEED_LIMIT_SIGNS`].
+    pub speed_limit_signs: Option<&'static [u32]>,
+    /// If set, `lights_warning` is also sent as on while `water` is at or above this
+    /// temperature, e.g. [`WATER_OVERHEAT`].
+    pub overheat_warning: Option<u32>,
+    /// Also send a single `BrakeLamp` command that is on if `fixing_brake` or
+    /// `lights_stop_brake` is on.
+    pub brake_lamp: bool,
+    /// Also send a `Neutral` command that is on while `gear_selector` is [`GEAR_NEUTRAL`],
+    /// whenever the gear selector enters or leaves neutral.
+    pub neutral_lamp: bool,
+    /// Also send a one-shot `DoorChime` command whenever a door opens.
+    pub door_chime: bool,
+    /// Only log the frame that would be sent and return an empty buffer.
+    pub dry_run: bool,
+    /// The line terminator of the frame, [`LineEnding::Lf`] by default.
+    pub line_ending: LineEnding,
+    /// The integrity byte sent before the line terminator, [`Framing::None`] by default.
+    pub framing: Framing,
+}
+
+/// The changed fields between two states as KOMSI commands, see [`VehicleState::state_diff`].
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StateDiff {
+    /// The commands for the changed fields, at most one per command kind.
+    commands: Vec<KomsiCommand>,
+}
+
+#[cfg(feature = "std")]
+impl StateDiff {
+    /// Returns the commands of this diff.
+    pub fn commands(&self) -> &[KomsiCommand] {
+        &self.commands
+    }
+
+    /// Returns true if no field changed.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Combines this diff with a `later` one into a single diff with the same effect.
+    ///
+    /// If both diffs contain a command of the same kind, the value of `later` wins.
+    /// A field that changed back to its old value is still included.
+    pub fn merge(&self, later: &StateDiff) -> StateDiff {
+        let mut commands = self.commands.clone();
+        for cmd in &later.commands {
+            match commands.iter_mut().find(|c| c.kind() == cmd.kind()) {
+                Some(existing) => *existing = *cmd,
+                None => commands.push(*cmd),
+            }
+        }
+        sort_by_dependencies(&mut commands);
+        StateDiff { commands }
+    }
+
+    /// Returns the KOMSI frame for this diff, empty if no field changed.
+    pub fn to_frame(&self) -> Vec<u8> {
+        KomsiCommand::build_frame(&self.commands)
+    }
+}
+
+/// A set of command kinds, e.g. the fields that are wired up on a panel,
+/// see [`VehicleState::compare_masked`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FieldMask(u128);
+
+impl FieldMask {
+    /// No command kinds.
+    pub const NONE: FieldMask = FieldMask(0);
+    /// All command kinds.
+    pub const ALL: FieldMask = FieldMask(u128::MAX);
+
+    /// Returns the mask of all command kinds of the given group.
+    pub fn group(group: CommandGroup) -> FieldMask {
+        KomsiCommandKind::ALL
+            .iter()
+            .filter(|kind| kind.group() == group)
+            .fold(Self::NONE, |mask, &kind| mask.with(kind))
+    }
+
+    /// Returns this mask with the given command kind added.
+    pub const fn with(self, kind: KomsiCommandKind) -> FieldMask {
+        FieldMask(self.0 | 1 << kind as u8)
+    }
+
+    /// Returns this mask with the given command kind removed.
+    pub const fn without(self, kind: KomsiCommandKind) -> FieldMask {
+        FieldMask(self.0 & !(1 << kind as u8))
+    }
+
+    /// Returns true if the mask contains the given command kind.
+    pub const fn contains(&self, kind: KomsiCommandKind) -> bool {
+        self.0 & 1 << kind as u8 != 0
+    }
+}
+
+/// A single changed field, see [`VehicleState::diff`].
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldChange {
+    /// The name of the changed field, e.g. `"speed"`.
+    pub field: &'static str,
+    /// The old value as sent (booleans as 0 or 1, date/time as YYYYMMDDHHMMSS).
+    pub old: u64,
+    /// The new value as sent.
+    pub new: u64,
+    /// The command kind the change is sent with.
+    pub command: KomsiCommandKind,
+}
+
 /// Represents the state of a vehicle.
 ///
 /// This struct holds various properties of a vehicle, such as speed, engine status,
 /// and light statuses. It can be used to track changes and generate KOMSI commands.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VehicleState {
     /// Ignition status (0 = Off, 1 = On)
     pub ignition: bool,
@@ -25,7 +287,7 @@ pub struct VehicleState {
     pub maxspeed: u32,
     /// Fuel level
     pub fuel: u8,
-    /// Indicator status (0 = Off, 1 = Left, 2 = Right, 3 = Both)
+    /// Indicator status (0 = Off, 1 = Left, 2 = Right, 3 = Both), see [`Indicator`]
     pub indicator: u8,
     /// Fixing brake / Parking brake status (0 = Released, 1 = Applied)
     pub fixing_brake: bool,
@@ -49,7 +311,8 @@ pub struct VehicleState {
     pub lights_high_beam: bool,
     /// Battery charging light status
     pub battery_light: bool,
-    /// Gear selector position
+    /// Gear selector position (0 = Neutral, 1 = Drive, 2 = Reverse, 3 = Park), see
+    /// [`GearSelector`], sent as `GearSelector` (`g`)
     pub gear_selector: u8,
     /// Door clearance status
     pub door_clearance: bool,
@@ -59,42 +322,124 @@ pub struct VehicleState {
     pub total_distance: u64,
     /// Total distance in kilometers.
     pub total_distance_km: u64,
+    /// Number of passengers on board
+    pub passengers: u32,
+    /// Air conditioning status (0 = Off, 1 = On)
+    pub ac_status: bool,
+    /// Kneeling status (0 = Normal level, 1 = Kneeled)
+    pub kneeling: bool,
+    /// Retarder stage (0 = Off, 1-5), higher values are sent as 5
+    pub retarder: u8,
+    /// Wheelchair ramp status (0 = Stowed, 1 = Deployed)
+    pub ramp: bool,
+    /// Door at which a stop is requested (0 = None, 1-4 = Door)
+    pub stop_request_door: u8,
+    /// The coolant water temperature, see [`CompareOptions::overheat_warning`].
+    pub water: u32,
+    /// The engine speed in revolutions per minute.
+    pub rpm: u32,
+    /// The air brake pressure.
+    pub pressure: u32,
+    /// The index of the upcoming stop on the route.
+    pub next_stop: u32,
+    /// The engine temperature.
+    pub temperature: u32,
+    /// The engine oil temperature.
+    pub oil: u32,
+    /// The simulator feeding the board, see [`VehicleState::init_command`].
+    pub simulator_type: u8,
 }
 
+/// The all-off state every board starts in.
+static OFF_BASELINE: VehicleState = VehicleState {
+    ignition: false,
+    engine: false,
+    doors: false,
+    speed: 0,
+    indicator: 0,
+    fixing_brake: false,
+    lights_warning: false,
+    lights_main: false,
+    lights_front_door: false,
+    lights_second_door: false,
+    lights_third_door: false,
+    lights_fourth_door: false,
+    lights_stop_request: false,
+    maxspeed: 0,
+    lights_high_beam: false,
+    fuel: 0,
+    lights_stop_brake: false,
+    battery_light: false,
+    door_clearance: false,
+    gear_selector: 0,
+    datetime: crate::komsi::KomsiDateTime {
+        year: 2000,
+        month: 1,
+        day: 1,
+        hour: 0,
+        min: 0,
+        sec: 0,
+    },
+    total_distance: 0,
+    total_distance_km: 0,
+    passengers: 0,
+    ac_status: false,
+    kneeling: false,
+    retarder: 0,
+    ramp: false,
+    stop_request_door: 0,
+    water: 0,
+    rpm: 0,
+    pressure: 0,
+    next_stop: 0,
+    temperature: 0,
+    oil: 0,
+    simulator_type: 0,
+};
+
 impl Default for VehicleState {
     fn default() -> Self {
-        Self {
-            ignition: false,
-            engine: false,
-            doors: false,
-            speed: 0,
-            indicator: 0,
-            fixing_brake: false,
-            lights_warning: false,
-            lights_main: false,
-            lights_front_door: false,
-            lights_second_door: false,
-            lights_third_door: false,
-            lights_fourth_door: false,
-            lights_stop_request: false,
-            maxspeed: 0,
-            lights_high_beam: false,
-            fuel: 0,
-            lights_stop_brake: false,
-            battery_light: false,
-            door_clearance: false,
-            gear_selector: 0,
-            datetime: crate::komsi::KomsiDateTime {
-                year: 2000,
-                month: 1,
-                day: 1,
-                hour: 0,
-                min: 0,
-                sec: 0,
-            },
-            total_distance: 0,
-            total_distance_km: 0,
-        }
+        OFF_BASELINE.clone()
+    }
+}
+
+/// Formats the state as space separated `key:value` pairs, e.g. for log files.
+impl core::fmt::Display for VehicleState {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "ignition:{} ", self.ignition)?;
+        write!(f, "engine:{} ", self.engine)?;
+        write!(f, "indicator:{} ", self.indicator)?;
+        write!(f, "fuel:{} ", self.fuel)?;
+        write!(f, "warn:{} ", self.lights_warning)?;
+        write!(f, "lights:{} ", self.lights_main)?;
+        write!(f, "high-beam:{} ", self.lights_high_beam)?;
+        write!(f, "stop:{} ", self.lights_stop_request)?;
+        write!(f, "fixing-brake:{} ", self.fixing_brake)?;
+        write!(f, "stop-brake:{} ", self.lights_stop_brake)?;
+        write!(f, "doors:{} ", self.doors)?;
+        write!(f, "door1:{} ", self.lights_front_door)?;
+        write!(f, "door2:{} ", self.lights_second_door)?;
+        write!(f, "door3:{} ", self.lights_third_door)?;
+        write!(f, "door4:{} ", self.lights_fourth_door)?;
+        write!(f, "speed:{} ", self.speed)?;
+        write!(f, "max-speed:{} ", self.maxspeed)?;
+        write!(f, "battery-light:{} ", self.battery_light)?;
+        write!(f, "door-clearance:{} ", self.door_clearance)?;
+        write!(f, "gear-selector:{} ", self.gear_selector)?;
+        write!(f, "passengers:{} ", self.passengers)?;
+        write!(f, "ac:{} ", self.ac_status)?;
+        write!(f, "kneeling:{} ", self.kneeling)?;
+        write!(f, "retarder:{} ", self.retarder)?;
+        write!(f, "ramp:{} ", self.ramp)?;
+        write!(f, "stop-door:{} ", self.stop_request_door)?;
+        write!(f, "water:{} ", self.water)?;
+        write!(f, "rpm:{} ", self.rpm)?;
+        write!(f, "pressure:{} ", self.pressure)?;
+        write!(f, "next-stop:{} ", self.next_stop)?;
+        write!(f, "temperature:{} ", self.temperature)?;
+        write!(f, "oil:{} ", self.oil)?;
+        write!(f, "simulator:{} ", self.simulator_type)?;
+        write!(f, "datetime:{:?}", self.datetime)
     }
 }
 
@@ -104,156 +449,832 @@ impl VehicleState {
         Self::default()
     }
 
-    /// Prints the current state to the console.
+    /// Creates a pseudo-random state from a seed, e.g. for property tests.
+    ///
+    /// The same seed always gives the same state. All values are within their valid ranges:
+    /// `doors` matches the door lights, `total_distance_km` matches `total_distance` and the
+    /// date/time is a valid date between 2000 and 2099.
+    pub fn from_seed(seed: u64) -> VehicleState {
+        // SplitMix64
+        let mut state = seed;
+        let mut next = |range: u64| {
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            (z ^ (z >> 31)) % range
+        };
+
+        let total_distance = next(10_000_000_000);
+        let mut new = VehicleState {
+            ignition: next(2) == 1,
+            engine: next(2) == 1,
+            doors: false,
+            speed: next(121) as u32,
+            maxspeed: next(121) as u32,
+            fuel: next(101) as u8,
+            indicator: next(4) as u8,
+            fixing_brake: next(2) == 1,
+            lights_warning: next(2) == 1,
+            lights_main: next(2) == 1,
+            lights_front_door: next(2) == 1,
+            lights_second_door: next(2) == 1,
+            lights_third_door: next(2) == 1,
+            lights_fourth_door: next(2) == 1,
+            lights_stop_request: next(2) == 1,
+            lights_stop_brake: next(2) == 1,
+            lights_high_beam: next(2) == 1,
+            battery_light: next(2) == 1,
+            gear_selector: next(4) as u8,
+            door_clearance: next(2) == 1,
+            datetime: crate::komsi::KomsiDateTime {
+                year: 2000 + next(100) as u16,
+                month: 1 + next(12) as u8,
+                day: 1 + next(28) as u8,
+                hour: next(24) as u8,
+                min: next(60) as u8,
+                sec: next(60) as u8,
+            },
+            total_distance,
+            total_distance_km: total_distance / 1000,
+            passengers: next(150) as u32,
+            ac_status: next(2) == 1,
+            kneeling: next(2) == 1,
+            retarder: next(MAX_RETARDER as u64 + 1) as u8,
+            ramp: next(2) == 1,
+            stop_request_door: next(5) as u8,
+            water: 60 + next(61) as u32,
+            rpm: next(3001) as u32,
+            pressure: next(11) as u32,
+            next_stop: next(30) as u32,
+            temperature: 60 + next(61) as u32,
+            oil: 60 + next(61) as u32,
+            simulator_type: next(3) as u8,
+        };
+        new.sync_doors_flag();
+        new
+    }
+
+    /// Returns a shared reference to the all-off default state.
+    ///
+    /// This can be used as comparison baseline for a known-off board without allocating a new state.
+    pub fn off_baseline() -> &'static VehicleState {
+        &OFF_BASELINE
+    }
+
+    /// Prints the current state to the console, see the [`Display`](core::fmt::Display) impl.
     #[cfg(feature = "std")]
     pub fn print(&self) {
-        print!("ignition:{} ", self.ignition);
-        print!("engine:{} ", self.engine);
-        print!("indicator:{} ", self.indicator);
-        print!("fuel:{} ", self.fuel);
-        print!("warn:{} ", self.lights_warning);
-        print!("lights:{} ", self.lights_main);
-        print!("high-beam:{} ", self.lights_high_beam);
-        print!("stop:{} ", self.lights_stop_request);
-        print!("fixing-brake:{} ", self.fixing_brake);
-        print!("stop-brake:{} ", self.lights_stop_brake);
-        print!("doors:{} ", self.doors);
-        print!("door1:{} ", self.lights_front_door);
-        print!("door2:{} ", self.lights_second_door);
-        print!("door3:{} ", self.lights_third_door);
-        print!("door4:{} ", self.lights_fourth_door);
-        print!("speed:{} ", self.speed);
-        print!("max-speed:{} ", self.maxspeed);
-        print!("battery-light:{} ", self.battery_light);
-        print!("door-clearance:{} ", self.door_clearance);
-        print!("gear-selector:{} ", self.gear_selector);
-        print!("datetime:{:?} ", self.datetime);
-        println!(" ");
+        println!("{self}");
+    }
+
+    /// Returns all numeric gauge values on a single line, e.g.
+    /// `spd=50 max=80 rpm=1500 fuel=70 water=85 press=8 temp=90 oil=4 odo=1234`.
+    ///
+    /// The odometer value is given in kilometers.
+    #[cfg(feature = "std")]
+    pub fn gauges_line(&self) -> String {
+        format!(
+            "spd={} max={} rpm={} fuel={} water={} press={} temp={} oil={} odo={}",
+            self.speed,
+            self.maxspeed,
+            self.rpm,
+            self.fuel,
+            self.water,
+            self.pressure,
+            self.temperature,
+            self.oil,
+            self.total_distance_km
+        )
     }
 
     /// Compares the current state with a new state and returns a buffer of KOMSI commands.
     ///
     /// If `force` is true, all fields will be included in the command buffer regardless of changes.
     /// An optional `logger` can be provided to log each change.
-    #[cfg(feature = "std")]
+    #[cfg(feature = "alloc")]
     pub fn compare(
         &self,
         new: &VehicleState,
         force: bool,
         logger: Option<&dyn VehicleLogger>,
     ) -> Vec<u8> {
-        let mut buffer: Vec<u8> = vec![0; 0];
+        KomsiCommand::build_frame(&self.compare_commands(new, force, logger))
+    }
 
-        if self.ignition != new.ignition || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "ignition", self.ignition as u8, new.ignition as u8
-                ));
-            }
-            let cmd = KomsiCommand::Ignition(new.ignition);
-            buffer.extend_from_slice(&cmd.build());
-        }
+    /// Compares the current state with a new state like [`VehicleState::compare`] and appends
+    /// a `Checksum` command before the EOL, see [`Framing::Checksum`].
+    ///
+    /// The checksum is the 8-bit sum of all command bytes of the line, see
+    /// [`line_checksum`](crate::komsi::line_checksum), e.g. `y55` is sent as `y55j227`.
+    /// Use [`VehicleState::compare_with_options`] to combine it with other options,
+    /// e.g. a different line ending. Returns an empty buffer if nothing changed.
+    #[cfg(feature = "alloc")]
+    pub fn compare_with_checksum(
+        &self,
+        new: &VehicleState,
+        force: bool,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<u8> {
+        let options = CompareOptions {
+            force,
+            framing: Framing::Checksum,
+            ..Default::default()
+        };
+        self.compare_with_options(new, &options, logger)
+    }
 
-        if self.engine != new.engine || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "engine", self.engine as u8, new.engine as u8
-                ));
-            }
-            let cmd = KomsiCommand::Engine(new.engine);
-            buffer.extend_from_slice(&cmd.build());
+    /// Compares the current state with a new state like [`VehicleState::compare`] and writes
+    /// the commands directly into `writer`, e.g. a serial port.
+    ///
+    /// The commands are serialized one by one on the stack without building a frame buffer.
+    /// Returns the number of bytes written, 0 if nothing changed.
+    #[cfg(feature = "std")]
+    pub fn write_compare<W: std::io::Write>(
+        &self,
+        new: &VehicleState,
+        force: bool,
+        logger: Option<&dyn VehicleLogger>,
+        writer: &mut W,
+    ) -> std::io::Result<usize> {
+        let commands = self.compare_commands(new, force, logger);
+        if commands.is_empty() {
+            return Ok(0);
+        }
+        // 32 bytes are enough for every command, see `KomsiCommand::build`
+        let mut packet = [0u8; 32];
+        let mut written = 0;
+        for cmd in &commands {
+            let len = cmd.to_packet(&mut packet);
+            writer.write_all(&packet[..len])?;
+            written += len;
         }
+        writer.write_all(b"\n")?;
+        Ok(written + 1)
+    }
 
-        if self.doors != new.doors || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "doors", self.doors as u8, new.doors as u8
-                ));
-            }
-            let cmd = KomsiCommand::PassengerDoorsOpen(new.doors);
-            buffer.extend_from_slice(&cmd.build());
+    /// Returns the frame that brings a freshly reset board from the all-off default to this state.
+    ///
+    /// This is the same as `VehicleState::default().compare(self, false, None)`, so fields
+    /// that are already at their default value are not sent.
+    #[cfg(feature = "std")]
+    pub fn from_default_frame(&self) -> Vec<u8> {
+        Self::off_baseline().compare(self, false, None)
+    }
+
+    /// Returns the number of commands [`VehicleState::compare`] would send for the new state,
+    /// without building them.
+    ///
+    /// The same rules apply, e.g. the odometer only counts if the kilometers change.
+    pub fn change_count(&self, new: &VehicleState) -> usize {
+        [
+            self.ignition != new.ignition,
+            self.engine != new.engine,
+            self.doors != new.doors,
+            self.fixing_brake != new.fixing_brake,
+            self.indicator != new.indicator,
+            self.lights_warning != new.lights_warning,
+            self.lights_main != new.lights_main,
+            self.lights_stop_request != new.lights_stop_request,
+            self.lights_stop_brake != new.lights_stop_brake,
+            self.lights_front_door != new.lights_front_door,
+            self.lights_second_door != new.lights_second_door,
+            self.lights_third_door != new.lights_third_door,
+            self.lights_fourth_door != new.lights_fourth_door,
+            self.lights_high_beam != new.lights_high_beam,
+            self.fuel != new.fuel,
+            self.speed != new.speed,
+            self.maxspeed != new.maxspeed,
+            self.battery_light != new.battery_light,
+            self.door_clearance != new.door_clearance,
+            self.passengers != new.passengers,
+            self.ac_status != new.ac_status,
+            self.kneeling != new.kneeling,
+            self.retarder.min(MAX_RETARDER) != new.retarder.min(MAX_RETARDER),
+            self.ramp != new.ramp,
+            self.stop_request_door != new.stop_request_door,
+            self.water != new.water,
+            self.rpm != new.rpm,
+            self.pressure != new.pressure,
+            self.next_stop != new.next_stop,
+            self.temperature != new.temperature,
+            self.oil != new.oil,
+            self.simulator_type != new.simulator_type,
+            self.total_distance_km != new.total_distance_km,
+            self.datetime.min != new.datetime.min,
+            self.gear_selector != new.gear_selector,
+        ]
+        .iter()
+        .filter(|&&changed| changed)
+        .count()
+    }
+
+    /// Compares the current state with a new state and returns the changed fields, e.g. to
+    /// show them in a table.
+    ///
+    /// The changes are the commands of [`VehicleState::compare`] in the same order.
+    #[cfg(feature = "std")]
+    pub fn diff(&self, new: &VehicleState) -> Vec<FieldChange> {
+        let old_values = self.compare_commands(self, true, None);
+        self.compare_commands(new, false, None)
+            .into_iter()
+            .map(|cmd| FieldChange {
+                field: field_name(cmd.kind()),
+                old: old_values
+                    .iter()
+                    .find(|old| old.kind() == cmd.kind())
+                    .map_or(0, |old| old.value()),
+                new: cmd.value(),
+                command: cmd.kind(),
+            })
+            .collect()
+    }
+
+    /// Compares the current state with a new state and returns the changes as a [`StateDiff`].
+    #[cfg(feature = "std")]
+    pub fn state_diff(&self, new: &VehicleState) -> StateDiff {
+        StateDiff {
+            commands: self.compare_commands(new, false, None),
         }
+    }
 
-        if self.fixing_brake != new.fixing_brake || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "fixing_brake", self.fixing_brake as u8, new.fixing_brake as u8
-                ));
-            }
-            let cmd = KomsiCommand::FixingBrake(new.fixing_brake);
-            buffer.extend_from_slice(&cmd.build());
+    /// Compares the current state with a new state like [`VehicleState::compare`] and returns
+    /// the commands in a canonical order, e.g. for golden test files.
+    ///
+    /// The commands are sorted by their command byte (`A`-`Z`, then `a`-`z`), dependencies
+    /// are then moved in front of the commands depending on them
+    /// (see [`KomsiCommandKind::dependencies`]). The result only depends on the two states.
+    #[cfg(feature = "std")]
+    pub fn compare_canonical(&self, new: &VehicleState, force: bool) -> Vec<u8> {
+        let mut commands = self.compare_commands(new, force, None);
+        commands.sort_by_key(|cmd| cmd.kind());
+        sort_by_dependencies(&mut commands);
+        KomsiCommand::build_frame(&commands)
+    }
+
+    /// Checks that all numeric fields with a limited range have a valid value.
+    ///
+    /// Checked are `indicator` and `gear_selector` (0-3, see [`Indicator`] and
+    /// [`GearSelector`]), `fuel` (0-100) and `stop_request_door` (0-4). The on/off fields are
+    /// booleans and always valid. `retarder` is not checked as too high stages are clamped.
+    /// Returns `KomsiError::InvalidField` with the name and value of the first invalid field.
+    pub fn validate(&self) -> Result<(), KomsiError> {
+        let invalid = |name: &'static str, value: u8| KomsiError::InvalidField(name, value as u64);
+        Indicator::try_from(self.indicator).map_err(|_| invalid("indicator", self.indicator))?;
+        GearSelector::try_from(self.gear_selector)
+            .map_err(|_| invalid("gear_selector", self.gear_selector))?;
+        if self.fuel > 100 {
+            return Err(invalid("fuel", self.fuel));
+        }
+        if self.stop_request_door > 4 {
+            return Err(invalid("stop_request_door", self.stop_request_door));
         }
+        Ok(())
+    }
 
-        if self.indicator != new.indicator || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "indicator", self.indicator, new.indicator
-                ));
-            }
-            let cmd = KomsiCommand::Indicator(new.indicator);
-            buffer.extend_from_slice(&cmd.build());
+    /// Returns the handshake frame that announces the simulator type, e.g. at session start.
+    ///
+    /// The frame contains only the `SimulatorType` command, regardless of any previous state.
+    #[cfg(feature = "std")]
+    pub fn init_command(&self) -> Vec<u8> {
+        KomsiCommand::build_frame(&[KomsiCommand::SimulatorType(self.simulator_type)])
+    }
+
+    /// Returns a frame that sends every command kind with value 0 (or off), e.g. to blank
+    /// a reconnected display.
+    ///
+    /// Unlike a forced [`VehicleState::compare`] this does not depend on any state and also
+    /// blanks the lamps without a field of their own (e.g. `Neutral`, `BrakeLamp` and
+    /// `DoorChime`). Not included are the date/time, which has no zero value, and the
+    /// protocol commands `ReadyQuery`, `SequenceNumber`, `Timestamp`, `Checksum` and `Crc`.
+    #[cfg(feature = "std")]
+    pub fn reset_commands() -> Vec<u8> {
+        use KomsiCommandKind as K;
+        let commands: Vec<KomsiCommand> = KomsiCommandKind::ALL
+            .iter()
+            .filter(|kind| {
+                !matches!(
+                    kind,
+                    K::DateTime
+                        | K::ReadyQuery
+                        | K::SequenceNumber
+                        | K::Timestamp
+                        | K::Checksum
+                        | K::Crc
+                )
+            })
+            .map(|&kind| {
+                KomsiCommand::from_value(kind, 0).expect("only date/time has no zero value")
+            })
+            .collect();
+        KomsiCommand::build_frame(&commands)
+    }
+
+    /// Returns the defined safe configuration used to recover after a lost connection.
+    ///
+    /// The warning lights are on and all doors are closed without door clearance,
+    /// all other fields (including ignition and engine) are kept.
+    pub fn safe_state(&self) -> VehicleState {
+        VehicleState {
+            lights_warning: true,
+            doors: false,
+            lights_front_door: false,
+            lights_second_door: false,
+            lights_third_door: false,
+            lights_fourth_door: false,
+            door_clearance: false,
+            ..self.clone()
         }
+    }
 
-        if self.lights_warning != new.lights_warning || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "lights_warning", self.lights_warning as u8, new.lights_warning as u8
-                ));
+    /// Returns a full snapshot of the [`VehicleState::safe_state`] to send after reconnecting.
+    #[cfg(feature = "std")]
+    pub fn safe_state_frame(&self) -> Vec<u8> {
+        let safe = self.safe_state();
+        safe.compare(&safe, true, None)
+    }
+
+    /// Sets the aggregate `doors` flag from the four individual door lights.
+    ///
+    /// `doors` becomes true if any door light is on and false if all are off.
+    pub fn sync_doors_flag(&mut self) {
+        self.doors = self.lights_front_door
+            || self.lights_second_door
+            || self.lights_third_door
+            || self.lights_fourth_door;
+    }
+
+    /// Returns true if a door (or the aggregate `doors` flag) is open now and was closed in `old`.
+    #[cfg(feature = "alloc")]
+    fn any_door_opened_since(&self, old: &VehicleState) -> bool {
+        let doors = |s: &VehicleState| {
+            [
+                s.doors,
+                s.lights_front_door,
+                s.lights_second_door,
+                s.lights_third_door,
+                s.lights_fourth_door,
+            ]
+        };
+        doors(old)
+            .iter()
+            .zip(doors(self))
+            .any(|(&was_open, is_open)| !was_open && is_open)
+    }
+
+    /// Applies a single KOMSI command to this state, setting the matching field.
+    ///
+    /// Commands without a matching field (e.g. `DebugMode`) are ignored.
+    /// An `Odometer` command sets `total_distance` and derives `total_distance_km` from it.
+    pub fn apply_command(&mut self, cmd: &KomsiCommand) {
+        match *cmd {
+            KomsiCommand::Ignition(v) => self.ignition = v,
+            KomsiCommand::Engine(v) => self.engine = v,
+            KomsiCommand::PassengerDoorsOpen(v) => self.doors = v,
+            KomsiCommand::Indicator(v) => self.indicator = v,
+            KomsiCommand::FixingBrake(v) => self.fixing_brake = v,
+            KomsiCommand::WarningLights(v) => self.lights_warning = v,
+            KomsiCommand::MainLights(v) => self.lights_main = v,
+            KomsiCommand::FrontDoor(v) => self.lights_front_door = v,
+            KomsiCommand::SecondDoor(v) => self.lights_second_door = v,
+            KomsiCommand::ThirdDoor(v) => self.lights_third_door = v,
+            KomsiCommand::FourthDoor(v) => self.lights_fourth_door = v,
+            KomsiCommand::StopRequest(v) => self.lights_stop_request = v,
+            KomsiCommand::StopBrake(v) => self.lights_stop_brake = v,
+            KomsiCommand::HighBeam(v) => self.lights_high_beam = v,
+            KomsiCommand::BatteryLight(v) => self.battery_light = v,
+            KomsiCommand::DoorClearance(v) => self.door_clearance = v,
+            KomsiCommand::GearSelector(v) => self.gear_selector = v,
+            KomsiCommand::Odometer(v) => {
+                self.total_distance = v;
+                self.total_distance_km = v / 1000;
             }
-            let cmd = KomsiCommand::WarningLights(new.lights_warning);
-            buffer.extend_from_slice(&cmd.build());
+            KomsiCommand::DateTime(v) => self.datetime = v,
+            KomsiCommand::MaxSpeed(v) => self.maxspeed = v,
+            KomsiCommand::Fuel(v) => self.fuel = v,
+            KomsiCommand::Speed(v) => self.speed = v,
+            KomsiCommand::Passengers(v) => self.passengers = v,
+            KomsiCommand::AirConditioning(v) => self.ac_status = v,
+            KomsiCommand::Kneeling(v) => self.kneeling = v,
+            KomsiCommand::Retarder(v) => self.retarder = v.min(MAX_RETARDER),
+            KomsiCommand::Ramp(v) => self.ramp = v,
+            KomsiCommand::StopRequestDoor(v) => self.stop_request_door = v,
+            KomsiCommand::Water(v) => self.water = v,
+            KomsiCommand::RPM(v) => self.rpm = v,
+            KomsiCommand::Pressure(v) => self.pressure = v,
+            KomsiCommand::NextStop(v) => self.next_stop = v,
+            KomsiCommand::Temperature(v) => self.temperature = v,
+            KomsiCommand::Oil(v) => self.oil = v,
+            KomsiCommand::SimulatorType(v) => self.simulator_type = v,
+            _ => {}
         }
+    }
 
-        if self.lights_main != new.lights_main || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "lights_main", self.lights_main as u8, new.lights_main as u8
-                ));
-            }
-            let cmd = KomsiCommand::MainLights(new.lights_main);
-            buffer.extend_from_slice(&cmd.build());
+    /// Applies a list of `(kind, value)` commands to this state in order.
+    ///
+    /// Values are converted like [`KomsiCommand::from_value`]. Stops at the first command
+    /// whose value does not fit its kind and returns `KomsiError::InvalidValueAt` with its
+    /// index; all commands before it have been applied.
+    pub fn apply_commands(&mut self, cmds: &[(KomsiCommandKind, u32)]) -> Result<(), KomsiError> {
+        for (i, &(kind, value)) in cmds.iter().enumerate() {
+            let cmd = KomsiCommand::from_value(kind, value as u64)
+                .map_err(|_| KomsiError::InvalidValueAt(i, kind))?;
+            self.apply_command(&cmd);
         }
+        Ok(())
+    }
 
-        if self.lights_stop_request != new.lights_stop_request || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "lights_stop_request",
-                    self.lights_stop_request as u8,
-                    new.lights_stop_request as u8
-                ));
-            }
-            let cmd = KomsiCommand::StopRequest(new.lights_stop_request);
-            buffer.extend_from_slice(&cmd.build());
+    /// Decodes a line received from the wire (see [`decode_komsi_line`]) and applies all
+    /// commands to this state like [`VehicleState::apply_command`].
+    ///
+    /// Commands without a matching field are ignored. If the line can not be decoded the
+    /// error is returned and the state is left unchanged.
+    #[cfg(feature = "std")]
+    pub fn apply_buffer(&mut self, bytes: &[u8]) -> Result<(), KomsiError> {
+        for cmd in decode_komsi_line(bytes)? {
+            self.apply_command(&cmd);
         }
+        Ok(())
+    }
 
-        if self.lights_stop_brake != new.lights_stop_brake || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "lights_stop_brake", self.lights_stop_brake as u8, new.lights_stop_brake as u8
-                ));
-            }
-            let cmd = KomsiCommand::StopBrake(new.lights_stop_brake);
-            buffer.extend_from_slice(&cmd.build());
+    /// Verifies that this state survives a roundtrip through the KOMSI wire format.
+    ///
+    /// All fields are serialized like `compare(force = true)`, parsed back and applied onto
+    /// a default state. `total_distance_km` is not transmitted and taken over unchanged.
+    /// Returns `KomsiError::RoundtripMismatch` if the reconstructed state differs.
+    #[cfg(feature = "std")]
+    pub fn roundtrip_check(&self) -> Result<(), KomsiError> {
+        let mut decoded = VehicleState::default();
+
+        for cmd in self.compare_commands(self, true, None) {
+            let buf = cmd.build();
+            let parsed = KomsiCommand::from_parts(buf[0] as char, &buf[1..])?;
+            decoded.apply_command(&parsed);
         }
+        decoded.total_distance_km = self.total_distance_km;
 
-        if self.lights_front_door != new.lights_front_door || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "lights_front_door", self.lights_front_door as u8, new.lights_front_door as u8
-                ));
-            }
-            let cmd = KomsiCommand::FrontDoor(new.lights_front_door);
-            buffer.extend_from_slice(&cmd.build());
+        if decoded == *self {
+            Ok(())
+        } else {
+            Err(KomsiError::RoundtripMismatch)
+        }
+    }
+
+    /// Finds the candidate board state that needs the fewest commands to reach this state.
+    ///
+    /// Returns the index of the chosen candidate and the command buffer bringing it to `self`,
+    /// or `None` if there are no candidates. On a tie the first candidate wins.
+    #[cfg(feature = "std")]
+    pub fn compare_to_closest(&self, candidates: &[VehicleState]) -> Option<(usize, Vec<u8>)> {
+        candidates
+            .iter()
+            .map(|c| c.compare_commands(self, false, None))
+            .enumerate()
+            .min_by_key(|(_, commands)| commands.len())
+            .map(|(i, commands)| (i, KomsiCommand::build_frame(&commands)))
+    }
+
+    /// Compares the current state with a new state like [`VehicleState::compare`],
+    /// using the given options.
+    ///
+    /// With `dry_run` the frame is only passed to the logger as `dry run: <frame>`
+    /// (if it is not empty) and an empty buffer is returned.
+    #[cfg(feature = "alloc")]
+    pub fn compare_with_options(
+        &self,
+        new: &VehicleState,
+        options: &CompareOptions,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<u8> {
+        let commands = self.compare_commands_with_options(new, options, logger);
+        if !options.dry_run {
+            return options.framing.build_frame(&commands, options.line_ending);
+        }
+        // log only the commands, without integrity check and line ending
+        let frame = KomsiCommand::build_frame(&commands);
+        if let (Some(l), Some((_eol, commands))) = (logger, frame.split_last()) {
+            l.log(format!("dry run: {}", String::from_utf8_lossy(commands)));
+        }
+        Vec::new()
+    }
+
+    /// Compares the current state with a new state and returns the list of KOMSI commands,
+    /// using the given options.
+    ///
+    /// With [`EngineOffSensors::Zero`] or [`EngineOffSensors::Suppress`] sensor changes are
+    /// not sent while the engine is off. When the engine is switched off all sensors are sent
+    /// once as 0 (`Zero`) and when it is switched on again all sensors are sent with their
+    /// current values.
+    #[cfg(feature = "alloc")]
+    pub fn compare_commands_with_options(
+        &self,
+        new: &VehicleState,
+        options: &CompareOptions,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<KomsiCommand> {
+        if let Some(signs) = options.speed_limit_signs {
+            // compare the displayed signs, so changes within the range of one sign are not sent
+            let mut old = self.clone();
+            let mut new = new.clone();
+            old.maxspeed = nearest_speed_limit_sign(old.maxspeed, signs);
+            new.maxspeed = nearest_speed_limit_sign(new.maxspeed, signs);
+            let options = CompareOptions {
+                speed_limit_signs: None,
+                ..*options
+            };
+            return old.compare_commands_with_options(&new, &options, logger);
+        }
+
+        if let Some(limit) = options.overheat_warning {
+            // compare the displayed lamp, so the lamp follows the water temperature
+            let mut old = self.clone();
+            let mut new = new.clone();
+            old.lights_warning |= old.water >= limit;
+            new.lights_warning |= new.water >= limit;
+            let options = CompareOptions {
+                overheat_warning: None,
+                ..*options
+            };
+            return old.compare_commands_with_options(&new, &options, logger);
+        }
+
+        let mut commands = self.compare_commands(new, options.force, logger);
+
+        if options.brake_lamp {
+            let old_lamp = self.fixing_brake || self.lights_stop_brake;
+            let new_lamp = new.fixing_brake || new.lights_stop_brake;
+            if old_lamp != new_lamp || options.force {
+                commands.push(KomsiCommand::BrakeLamp(new_lamp));
+            }
+        }
+
+        if options.neutral_lamp {
+            let old_neutral = self.gear_selector == GEAR_NEUTRAL;
+            let new_neutral = new.gear_selector == GEAR_NEUTRAL;
+            if old_neutral != new_neutral || options.force {
+                commands.push(KomsiCommand::Neutral(new_neutral));
+            }
+        }
+
+        // the chime is a trigger on every door opening, it is never sent as off
+        if options.door_chime && new.any_door_opened_since(self) {
+            commands.push(KomsiCommand::DoorChime(true));
+        }
+
+        let mode = options.engine_off_sensors;
+        if mode == EngineOffSensors::Send || (self.engine && new.engine && !options.force) {
+            return commands;
+        }
+
+        commands.retain(|cmd| cmd.group() != CommandGroup::Sensors);
+        let engine_switched = options.force || self.engine != new.engine;
+        if engine_switched && (new.engine || mode == EngineOffSensors::Zero) {
+            let sensors = new
+                .compare_commands(new, true, None)
+                .into_iter()
+                .filter(|cmd| cmd.group() == CommandGroup::Sensors);
+            for cmd in sensors {
+                commands.push(if new.engine {
+                    cmd
+                } else {
+                    cmd.map_value(&|_| 0)
+                });
+            }
+        }
+        commands
+    }
+
+    /// Compares the current state with a new state like [`VehicleState::compare`], but sends
+    /// the command kinds listed in `deltas` only if their value changed by more than the
+    /// given amount.
+    ///
+    /// The change is measured on the transmitted value (see [`KomsiCommand::value`]),
+    /// command kinds without a delta are sent on every change.
+    #[cfg(feature = "std")]
+    pub fn compare_with_deltas(
+        &self,
+        new: &VehicleState,
+        deltas: &BTreeMap<KomsiCommandKind, u32>,
+    ) -> Vec<u8> {
+        let old_values: BTreeMap<KomsiCommandKind, u64> = self
+            .compare_commands(self, true, None)
+            .iter()
+            .map(|cmd| (cmd.kind(), cmd.value()))
+            .collect();
+
+        let mut commands = self.compare_commands(new, false, None);
+        commands.retain(
+            |cmd| match (deltas.get(&cmd.kind()), old_values.get(&cmd.kind())) {
+                (Some(&delta), Some(&old)) => old.abs_diff(cmd.value()) > delta as u64,
+                _ => true,
+            },
+        );
+        KomsiCommand::build_frame(&commands)
+    }
+
+    /// Compares the current state with a new state and returns a buffer with only the commands
+    /// a board with the given firmware version supports.
+    ///
+    /// See [`KomsiCommandKind::min_firmware_version`](crate::komsi::KomsiCommandKind::min_firmware_version).
+    #[cfg(feature = "std")]
+    pub fn compare_for_version(
+        &self,
+        new: &VehicleState,
+        firmware_version: u16,
+        force: bool,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<u8> {
+        let mut commands = self.compare_commands(new, force, logger);
+        commands.retain(|cmd| cmd.kind().is_supported_by(firmware_version));
+        KomsiCommand::build_frame(&commands)
+    }
+
+    /// Compares the current state with a new state like [`VehicleState::compare`], but only
+    /// for the command kinds in `mask`.
+    ///
+    /// Changes of the other fields are neither logged nor sent. With `force` all fields of the
+    /// mask are sent.
+    #[cfg(feature = "std")]
+    pub fn compare_masked(
+        &self,
+        new: &VehicleState,
+        mask: FieldMask,
+        force: bool,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<u8> {
+        // take over only the masked fields, so the others compare as unchanged
+        let mut masked = self.clone();
+        for cmd in new.compare_commands(new, true, None) {
+            if mask.contains(cmd.kind()) {
+                masked.apply_command(&cmd);
+            }
+        }
+        let mut commands = self.compare_commands(&masked, force, logger);
+        commands.retain(|cmd| mask.contains(cmd.kind()));
+        KomsiCommand::build_frame(&commands)
+    }
+
+    /// Compares the current state with a new state and returns a buffer containing only
+    /// the safety-critical commands (brakes, doors and warning lights).
+    ///
+    /// All other changes are dropped, which makes this suitable for a minimal-bandwidth safe mode.
+    #[cfg(feature = "std")]
+    pub fn critical_changes(&self, new: &VehicleState) -> Vec<u8> {
+        let mut commands = self.compare_commands(new, false, None);
+        commands.retain(|cmd| cmd.group().is_safety_critical());
+        KomsiCommand::build_frame(&commands)
+    }
+
+    /// Returns the command group with the most changes between the current and the new state,
+    /// e.g. as an activity label, or `None` if nothing changed (idle).
+    ///
+    /// On a tie the group declared first in [`CommandGroup`] wins.
+    #[cfg(feature = "std")]
+    pub fn frame_category(&self, new: &VehicleState) -> Option<CommandGroup> {
+        let commands = self.compare_commands(new, false, None);
+        let mut best: Option<(CommandGroup, usize)> = None;
+        for group in [
+            CommandGroup::Driving,
+            CommandGroup::Lights,
+            CommandGroup::Warnings,
+            CommandGroup::Doors,
+            CommandGroup::Brakes,
+            CommandGroup::Sensors,
+            CommandGroup::System,
+        ] {
+            let count = commands.iter().filter(|cmd| cmd.group() == group).count();
+            if count > 0 && best.is_none_or(|(_, max)| count > max) {
+                best = Some((group, count));
+            }
+        }
+        best.map(|(group, _)| group)
+    }
+
+    /// Compares the current state with a new state and returns the list of KOMSI commands
+    /// for all changed fields.
+    ///
+    /// `force` and `logger` behave as in [`VehicleState::compare`].
+    /// Commands are ordered so their dependencies come first, e.g. the door clearance is sent
+    /// before any door command.
+    #[cfg(feature = "alloc")]
+    pub fn compare_commands(
+        &self,
+        new: &VehicleState,
+        force: bool,
+        logger: Option<&dyn VehicleLogger>,
+    ) -> Vec<KomsiCommand> {
+        let mut commands: Vec<KomsiCommand> = Vec::new();
+
+        if self.ignition != new.ignition || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "ignition", self.ignition as u8, new.ignition as u8
+                ));
+            }
+            let cmd = KomsiCommand::Ignition(new.ignition);
+            commands.push(cmd);
+        }
+
+        if self.engine != new.engine || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "engine", self.engine as u8, new.engine as u8
+                ));
+            }
+            let cmd = KomsiCommand::Engine(new.engine);
+            commands.push(cmd);
+        }
+
+        if self.doors != new.doors || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "doors", self.doors as u8, new.doors as u8
+                ));
+            }
+            let cmd = KomsiCommand::PassengerDoorsOpen(new.doors);
+            commands.push(cmd);
+        }
+
+        if self.fixing_brake != new.fixing_brake || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "fixing_brake", self.fixing_brake as u8, new.fixing_brake as u8
+                ));
+            }
+            let cmd = KomsiCommand::FixingBrake(new.fixing_brake);
+            commands.push(cmd);
+        }
+
+        if self.indicator != new.indicator || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "indicator", self.indicator, new.indicator
+                ));
+            }
+            let cmd = KomsiCommand::Indicator(new.indicator);
+            commands.push(cmd);
+        }
+
+        if self.lights_warning != new.lights_warning || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "lights_warning", self.lights_warning as u8, new.lights_warning as u8
+                ));
+            }
+            let cmd = KomsiCommand::WarningLights(new.lights_warning);
+            commands.push(cmd);
+        }
+
+        if self.lights_main != new.lights_main || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "lights_main", self.lights_main as u8, new.lights_main as u8
+                ));
+            }
+            let cmd = KomsiCommand::MainLights(new.lights_main);
+            commands.push(cmd);
+        }
+
+        if self.lights_stop_request != new.lights_stop_request || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "lights_stop_request",
+                    self.lights_stop_request as u8,
+                    new.lights_stop_request as u8
+                ));
+            }
+            let cmd = KomsiCommand::StopRequest(new.lights_stop_request);
+            commands.push(cmd);
+        }
+
+        if self.lights_stop_brake != new.lights_stop_brake || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "lights_stop_brake", self.lights_stop_brake as u8, new.lights_stop_brake as u8
+                ));
+            }
+            let cmd = KomsiCommand::StopBrake(new.lights_stop_brake);
+            commands.push(cmd);
+        }
+
+        if self.lights_front_door != new.lights_front_door || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "lights_front_door", self.lights_front_door as u8, new.lights_front_door as u8
+                ));
+            }
+            let cmd = KomsiCommand::FrontDoor(new.lights_front_door);
+            commands.push(cmd);
         }
 
         if self.lights_second_door != new.lights_second_door || force {
@@ -266,7 +1287,7 @@ impl VehicleState {
                 ));
             }
             let cmd = KomsiCommand::SecondDoor(new.lights_second_door);
-            buffer.extend_from_slice(&cmd.build());
+            commands.push(cmd);
         }
 
         if self.lights_third_door != new.lights_third_door || force {
@@ -277,7 +1298,20 @@ impl VehicleState {
                 ));
             }
             let cmd = KomsiCommand::ThirdDoor(new.lights_third_door);
-            buffer.extend_from_slice(&cmd.build());
+            commands.push(cmd);
+        }
+
+        if self.lights_fourth_door != new.lights_fourth_door || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "lights_fourth_door",
+                    self.lights_fourth_door as u8,
+                    new.lights_fourth_door as u8
+                ));
+            }
+            let cmd = KomsiCommand::FourthDoor(new.lights_fourth_door);
+            commands.push(cmd);
         }
 
         if self.lights_high_beam != new.lights_high_beam || force {
@@ -288,144 +1322,1014 @@ impl VehicleState {
                 ));
             }
             let cmd = KomsiCommand::HighBeam(new.lights_high_beam);
-            buffer.extend_from_slice(&cmd.build());
+            commands.push(cmd);
+        }
+
+        if self.fuel != new.fuel || force {
+            if let Some(l) = logger {
+                l.log(format!("{}: {} -> {} ", "fuel", self.fuel, new.fuel));
+            }
+            let cmd = KomsiCommand::Fuel(new.fuel);
+            commands.push(cmd);
+        }
+
+        if self.speed != new.speed || force {
+            if let Some(l) = logger {
+                l.log(format!("{}: {} -> {} ", "speed", self.speed, new.speed));
+            }
+            let cmd = KomsiCommand::Speed(new.speed);
+            commands.push(cmd);
+        }
+
+        if self.maxspeed != new.maxspeed || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "maxspeed", self.maxspeed, new.maxspeed
+                ));
+            }
+            let cmd = KomsiCommand::MaxSpeed(new.maxspeed);
+            commands.push(cmd);
+        }
+
+        if self.battery_light != new.battery_light || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "battery_light", self.battery_light as u8, new.battery_light as u8
+                ));
+            }
+            let cmd = KomsiCommand::BatteryLight(new.battery_light);
+            commands.push(cmd);
         }
 
-        if self.fuel != new.fuel || force {
-            if let Some(l) = logger {
-                l.log(format!("{}: {} -> {} ", "fuel", self.fuel, new.fuel));
-            }
-            let cmd = KomsiCommand::Fuel(new.fuel);
-            buffer.extend_from_slice(&cmd.build());
-        }
+        if self.door_clearance != new.door_clearance || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "door_clearance", self.door_clearance as u8, new.door_clearance as u8
+                ));
+            }
+            let cmd = KomsiCommand::DoorClearance(new.door_clearance);
+            commands.push(cmd);
+        }
+
+        if self.passengers != new.passengers || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "passengers", self.passengers, new.passengers
+                ));
+            }
+            let cmd = KomsiCommand::Passengers(new.passengers);
+            commands.push(cmd);
+        }
+
+        if self.ac_status != new.ac_status || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "ac_status", self.ac_status as u8, new.ac_status as u8
+                ));
+            }
+            let cmd = KomsiCommand::AirConditioning(new.ac_status);
+            commands.push(cmd);
+        }
+
+        if self.kneeling != new.kneeling || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "kneeling", self.kneeling as u8, new.kneeling as u8
+                ));
+            }
+            let cmd = KomsiCommand::Kneeling(new.kneeling);
+            commands.push(cmd);
+        }
+
+        let old_retarder = self.retarder.min(MAX_RETARDER);
+        let new_retarder = new.retarder.min(MAX_RETARDER);
+        if old_retarder != new_retarder || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "retarder", old_retarder, new_retarder
+                ));
+            }
+            let cmd = KomsiCommand::Retarder(new_retarder);
+            commands.push(cmd);
+        }
+
+        if self.ramp != new.ramp || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "ramp", self.ramp as u8, new.ramp as u8
+                ));
+            }
+            let cmd = KomsiCommand::Ramp(new.ramp);
+            commands.push(cmd);
+        }
+
+        if self.stop_request_door != new.stop_request_door || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "stop_request_door", self.stop_request_door, new.stop_request_door
+                ));
+            }
+            let cmd = KomsiCommand::StopRequestDoor(new.stop_request_door);
+            commands.push(cmd);
+        }
+
+        if self.water != new.water || force {
+            if let Some(l) = logger {
+                l.log(format!("{}: {} -> {} ", "water", self.water, new.water));
+            }
+            let cmd = KomsiCommand::Water(new.water);
+            commands.push(cmd);
+        }
+
+        if self.rpm != new.rpm || force {
+            if let Some(l) = logger {
+                l.log(format!("{}: {} -> {} ", "rpm", self.rpm, new.rpm));
+            }
+            let cmd = KomsiCommand::RPM(new.rpm);
+            commands.push(cmd);
+        }
+
+        if self.pressure != new.pressure || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "pressure", self.pressure, new.pressure
+                ));
+            }
+            let cmd = KomsiCommand::Pressure(new.pressure);
+            commands.push(cmd);
+        }
+
+        if self.next_stop != new.next_stop || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "next_stop", self.next_stop, new.next_stop
+                ));
+            }
+            let cmd = KomsiCommand::NextStop(new.next_stop);
+            commands.push(cmd);
+        }
+
+        if self.temperature != new.temperature || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "temperature", self.temperature, new.temperature
+                ));
+            }
+            let cmd = KomsiCommand::Temperature(new.temperature);
+            commands.push(cmd);
+        }
+
+        if self.oil != new.oil || force {
+            if let Some(l) = logger {
+                l.log(format!("{}: {} -> {} ", "oil", self.oil, new.oil));
+            }
+            let cmd = KomsiCommand::Oil(new.oil);
+            commands.push(cmd);
+        }
+
+        if self.simulator_type != new.simulator_type || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "simulator_type", self.simulator_type, new.simulator_type
+                ));
+            }
+            let cmd = KomsiCommand::SimulatorType(new.simulator_type);
+            commands.push(cmd);
+        }
+
+        // we send only the total_distance if total_distance_km is changing
+        // we do not want to send to many messages
+        // the meters should be counted and increased in the client
+        if self.total_distance_km != new.total_distance_km || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "odometer", self.total_distance_km as u8, new.total_distance_km as u8
+                ));
+            }
+            let cmd = KomsiCommand::Odometer(new.total_distance);
+            commands.push(cmd);
+        }
+
+        // we send only the datetime if the minute value is changing
+        // we do not want to send too many messages
+        // the time should be increased in the client every second
+        if self.datetime.min != new.datetime.min || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {}:{}:{} -> {}:{}:{} ",
+                    "datetime",
+                    self.datetime.hour,
+                    self.datetime.min,
+                    self.datetime.sec,
+                    new.datetime.hour,
+                    new.datetime.min,
+                    new.datetime.sec,
+                ));
+            }
+            let cmd = KomsiCommand::DateTime(new.datetime);
+            commands.push(cmd);
+        }
+
+        // the gear selector position is sent on the free code g (firmware version 2)
+        if self.gear_selector != new.gear_selector || force {
+            if let Some(l) = logger {
+                l.log(format!(
+                    "{}: {} -> {} ",
+                    "gear_selector", self.gear_selector, new.gear_selector
+                ));
+            }
+            let cmd = KomsiCommand::GearSelector(new.gear_selector);
+            commands.push(cmd);
+        }
+
+        sort_by_dependencies(&mut commands);
+        commands
+    }
+}
+
+/// Returns the name of the field that is sent with the given command kind.
+#[cfg(feature = "std")]
+pub(crate) fn field_name(kind: KomsiCommandKind) -> &'static str {
+    use KomsiCommandKind as K;
+    match kind {
+        K::Ignition => "ignition",
+        K::Engine => "engine",
+        K::PassengerDoorsOpen => "doors",
+        K::FixingBrake => "fixing_brake",
+        K::Indicator => "indicator",
+        K::WarningLights => "lights_warning",
+        K::MainLights => "lights_main",
+        K::StopRequest => "lights_stop_request",
+        K::StopBrake => "lights_stop_brake",
+        K::FrontDoor => "lights_front_door",
+        K::SecondDoor => "lights_second_door",
+        K::ThirdDoor => "lights_third_door",
+        K::FourthDoor => "lights_fourth_door",
+        K::HighBeam => "lights_high_beam",
+        K::Fuel => "fuel",
+        K::Speed => "speed",
+        K::MaxSpeed => "maxspeed",
+        K::BatteryLight => "battery_light",
+        K::DoorClearance => "door_clearance",
+        K::Passengers => "passengers",
+        K::AirConditioning => "ac_status",
+        K::Kneeling => "kneeling",
+        K::Retarder => "retarder",
+        K::Ramp => "ramp",
+        K::StopRequestDoor => "stop_request_door",
+        K::Water => "water",
+        K::RPM => "rpm",
+        K::Pressure => "pressure",
+        K::NextStop => "next_stop",
+        K::Temperature => "temperature",
+        K::Oil => "oil",
+        K::SimulatorType => "simulator_type",
+        K::Odometer => "total_distance",
+        K::DateTime => "datetime",
+        K::GearSelector => "gear_selector",
+        // commands without a field of their own
+        _ => kind.name(),
+    }
+}
+
+/// Generates a consuming `with_*` setter for each field, so states can be built fluently:
+/// `VehicleState::new().with_ignition(true).with_speed(50)`.
+macro_rules! define_with_methods {
+    ($($field:ident: $ty:ty => $method:ident),* $(,)?) => {
+        impl VehicleState {
+            $(
+                #[doc = concat!("Sets `", stringify!($field), "` and returns the changed state.")]
+                pub fn $method(mut self, $field: $ty) -> Self {
+                    self.$field = $field;
+                    self
+                }
+            )*
+        }
+    };
+}
+
+define_with_methods! {
+    ignition: bool => with_ignition,
+    engine: bool => with_engine,
+    doors: bool => with_doors,
+    speed: u32 => with_speed,
+    maxspeed: u32 => with_maxspeed,
+    fuel: u8 => with_fuel,
+    indicator: u8 => with_indicator,
+    fixing_brake: bool => with_fixing_brake,
+    lights_warning: bool => with_lights_warning,
+    lights_main: bool => with_lights_main,
+    lights_front_door: bool => with_lights_front_door,
+    lights_second_door: bool => with_lights_second_door,
+    lights_third_door: bool => with_lights_third_door,
+    lights_fourth_door: bool => with_lights_fourth_door,
+    lights_stop_request: bool => with_lights_stop_request,
+    lights_stop_brake: bool => with_lights_stop_brake,
+    lights_high_beam: bool => with_lights_high_beam,
+    battery_light: bool => with_battery_light,
+    gear_selector: u8 => with_gear_selector,
+    door_clearance: bool => with_door_clearance,
+    datetime: crate::komsi::KomsiDateTime => with_datetime,
+    total_distance: u64 => with_total_distance,
+    total_distance_km: u64 => with_total_distance_km,
+    passengers: u32 => with_passengers,
+    ac_status: bool => with_ac_status,
+    kneeling: bool => with_kneeling,
+    retarder: u8 => with_retarder,
+    ramp: bool => with_ramp,
+    stop_request_door: u8 => with_stop_request_door,
+    water: u32 => with_water,
+    rpm: u32 => with_rpm,
+    pressure: u32 => with_pressure,
+    next_stop: u32 => with_next_stop,
+    temperature: u32 => with_temperature,
+    oil: u32 => with_oil,
+    simulator_type: u8 => with_simulator_type,
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::komsi::{line_checksum, verify_crc8};
+    use std::sync::{Arc, Mutex};
+
+    struct TestLogger {
+        logs: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl VehicleLogger for TestLogger {
+        fn log(&self, msg: String) {
+            self.logs.lock().unwrap().push(msg);
+        }
+    }
+
+    #[test]
+    fn test_vehicle_state_new() {
+        let state = VehicleState::new();
+        assert!(!state.ignition);
+        assert_eq!(state.speed, 0);
+    }
+
+    #[test]
+    fn test_from_seed() {
+        assert_eq!(VehicleState::from_seed(42), VehicleState::from_seed(42));
+        assert_ne!(VehicleState::from_seed(42), VehicleState::from_seed(43));
+
+        for seed in 0..100 {
+            let state = VehicleState::from_seed(seed);
+            assert!(state.indicator <= 3 && state.fuel <= 100);
+            assert_eq!(state.roundtrip_check(), Ok(()), "seed {seed}");
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_roundtrip() {
+        let state = VehicleState::from_seed(7);
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(json.contains(&format!("\"speed\":{}", state.speed)));
+        assert!(json.contains("\"datetime\":{\"year\":"));
+
+        let decoded: VehicleState = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, state);
+    }
+
+    #[test]
+    fn test_off_baseline() {
+        assert_eq!(*VehicleState::off_baseline(), VehicleState::default());
+
+        let mut new = VehicleState::new();
+        new.ignition = true;
+        let buffer = VehicleState::off_baseline().compare(&new, false, None);
+        assert_eq!(buffer, b"A1\n");
+    }
+
+    #[test]
+    fn test_from_default_frame() {
+        assert!(VehicleState::new().from_default_frame().is_empty());
+
+        let mut state = VehicleState::new();
+        state.engine = true;
+        state.maxspeed = 50;
+        assert_eq!(state.from_default_frame(), b"B1s50\n");
+    }
+
+    #[test]
+    fn test_compare_rpm() {
+        let old = VehicleState::new().with_engine(true);
+        let new = old.clone().with_rpm(1500);
+        assert_eq!(old.compare(&new, false, None), b"t1500\n");
+        assert!(new.compare(&new, false, None).is_empty());
+
+        // the gauge falls to 0 when the engine is switched off
+        let options = CompareOptions {
+            engine_off_sensors: EngineOffSensors::Zero,
+            ..Default::default()
+        };
+        let off = new.clone().with_engine(false);
+        let commands = new.compare_commands_with_options(&off, &options, None);
+        assert!(commands.contains(&KomsiCommand::RPM(0)));
+    }
+
+    #[test]
+    fn test_compare_pressure() {
+        let old = VehicleState::new();
+        let new = old.clone().with_pressure(8);
+        assert_eq!(old.compare(&new, false, None), [117, 56, 10]);
+    }
+
+    #[test]
+    fn test_compare_next_stop() {
+        let mut old = VehicleState::new();
+        for stop in 1..=3 {
+            let new = old.clone().with_next_stop(stop);
+            assert_eq!(
+                old.compare(&new, false, None),
+                format!("n{stop}\n").as_bytes()
+            );
+            old = new;
+        }
+    }
+
+    #[test]
+    fn test_compare_temperature() {
+        let state = VehicleState::new().with_temperature(85);
+        let frame = state.compare(&state, true, None);
+        assert!(frame.windows(3).any(|w| w == b"v85"));
+        assert_eq!(VehicleState::new().compare(&state, false, None), b"v85\n");
+    }
+
+    #[test]
+    fn test_compare_water() {
+        let state = VehicleState::new().with_water(88).with_temperature(95);
+        let frame = state.compare(&state, true, None);
+        assert!(frame.windows(3).any(|w| w == b"z88"));
+        assert!(frame.windows(3).any(|w| w == b"v95"));
+        assert_eq!(
+            state.compare(&state.clone().with_water(90), false, None),
+            b"z90\n"
+        );
+    }
+
+    #[test]
+    fn test_compare_oil() {
+        let old = VehicleState::new().with_oil(90);
+        assert!(old.compare(&old.clone(), false, None).is_empty());
+
+        let new = old.clone().with_oil(95);
+        assert_eq!(old.compare(&new, false, None), [119, b'9', b'5', 10]);
+    }
+
+    #[test]
+    fn test_gauges_line() {
+        let mut state = VehicleState::new();
+        state.speed = 50;
+        state.maxspeed = 80;
+        state.rpm = 1500;
+        state.fuel = 70;
+        state.water = 85;
+        state.pressure = 8;
+        state.temperature = 90;
+        state.oil = 4;
+        state.total_distance_km = 1234;
+        assert_eq!(
+            state.gauges_line(),
+            "spd=50 max=80 rpm=1500 fuel=70 water=85 press=8 temp=90 oil=4 odo=1234"
+        );
+    }
+
+    #[test]
+    fn test_compare_no_change() {
+        let old = VehicleState::new();
+        let new = VehicleState::new();
+        let buffer = old.compare(&new, false, None);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_compare_with_changes() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.ignition = true;
+        new.speed = 50;
+
+        let buffer = old.compare(&new, false, None);
+        let expected = vec![65, 49, 121, 53, 48, 10];
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn test_compare_door_clearance_first() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.lights_front_door = true;
+        new.door_clearance = true;
+
+        let buffer = old.compare(&new, false, None);
+        assert_eq!(buffer, b"P1H1\n");
+    }
+
+    #[test]
+    fn test_expected_frames() {
+        use core::time::Duration;
+        let start = VehicleState::new().with_ignition(true);
+        let timeline = [
+            (Duration::ZERO, start.clone()),
+            (Duration::from_millis(100), start.clone()),
+            (Duration::from_millis(200), start.clone().with_speed(20)),
+            (Duration::from_millis(300), start.clone().with_speed(25)),
+        ];
+        let frames = expected_frames(&timeline);
+        assert_eq!(
+            frames,
+            [
+                (Duration::ZERO, start.compare(&start, true, None)),
+                (Duration::from_millis(200), b"y20\n".to_vec()),
+                (Duration::from_millis(300), b"y25\n".to_vec()),
+            ]
+        );
+        assert!(expected_frames(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_with_methods() {
+        let built = VehicleState::new()
+            .with_ignition(true)
+            .with_engine(true)
+            .with_speed(50)
+            .with_indicator(2)
+            .with_lights_main(true)
+            .with_passengers(12);
+
+        let mut state = VehicleState::new();
+        state.ignition = true;
+        state.engine = true;
+        state.speed = 50;
+        state.indicator = 2;
+        state.lights_main = true;
+        state.passengers = 12;
+        assert_eq!(built, state);
+    }
+
+    #[test]
+    fn test_display() {
+        let state = VehicleState {
+            speed: 42,
+            lights_main: true,
+            ..VehicleState::new()
+        };
+        let text = format!("{state}");
+        assert!(text.starts_with("ignition:false engine:false indicator:0 fuel:0 warn:false "));
+        assert!(text.contains(" lights:true "));
+        assert!(text.contains(" speed:42 max-speed:0 "));
+        assert!(text.ends_with(&format!("datetime:{:?}", state.datetime)));
+    }
+
+    #[test]
+    fn test_write_compare() {
+        let old = VehicleState::from_seed(1);
+        let new = VehicleState::from_seed(2);
+        let mut out: Vec<u8> = Vec::new();
+        let written = old.write_compare(&new, false, None, &mut out).unwrap();
+        assert_eq!(out, old.compare(&new, false, None));
+        assert_eq!(written, out.len());
+
+        out.clear();
+        let written = new.write_compare(&new, true, None, &mut out).unwrap();
+        assert_eq!(out, new.compare(&new, true, None));
+        assert_eq!(written, out.len());
+
+        out.clear();
+        assert_eq!(new.write_compare(&new, false, None, &mut out).unwrap(), 0);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_compare_masked() {
+        let lights = FieldMask::group(CommandGroup::Lights);
+        let old = VehicleState::new();
+        let new = old.clone().with_speed(50).with_lights_main(true);
+        assert_eq!(old.compare_masked(&new, lights, false, None), b"G1\n");
+        assert_eq!(
+            old.compare_masked(&new.clone().with_lights_main(false), lights, false, None),
+            b""
+        );
+        assert_eq!(
+            old.compare_masked(&new, FieldMask::ALL, false, None),
+            old.compare(&new, false, None)
+        );
+
+        let mask = FieldMask::NONE.with(KomsiCommandKind::Speed);
+        assert!(mask.contains(KomsiCommandKind::Speed));
+        assert!(
+            !mask
+                .without(KomsiCommandKind::Speed)
+                .contains(KomsiCommandKind::Speed)
+        );
+        assert_eq!(old.compare_masked(&new, mask, true, None), b"y50\n");
+    }
+
+    #[test]
+    fn test_change_count() {
+        let old = VehicleState::new();
+        assert_eq!(old.change_count(&old), 0);
+
+        let mut new = old.clone().with_speed(50).with_fuel(80).with_retarder(9);
+        assert_eq!(old.change_count(&new), 3);
+        // only the kilometers count for the odometer, like in compare
+        new.total_distance = 999;
+        assert_eq!(old.change_count(&new), 3);
+
+        for seed in 0..20 {
+            let old = VehicleState::from_seed(seed);
+            let new = VehicleState::from_seed(seed + 100);
+            assert_eq!(
+                old.change_count(&new),
+                old.compare_commands(&new, false, None).len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = VehicleState::new().with_speed(30);
+        let new = old.clone().with_speed(50).with_lights_main(true);
+        assert_eq!(
+            old.diff(&new),
+            [
+                FieldChange {
+                    field: "lights_main",
+                    old: 0,
+                    new: 1,
+                    command: KomsiCommandKind::MainLights,
+                },
+                FieldChange {
+                    field: "speed",
+                    old: 30,
+                    new: 50,
+                    command: KomsiCommandKind::Speed,
+                },
+            ]
+        );
+        assert!(new.diff(&new).is_empty());
+    }
+
+    #[test]
+    fn test_state_diff_merge() {
+        let a = VehicleState::new();
+        let b = VehicleState {
+            speed: 30,
+            lights_main: true,
+            ..a.clone()
+        };
+        let c = VehicleState {
+            speed: 45,
+            doors: true,
+            ..b.clone()
+        };
+
+        let merged = a.state_diff(&b).merge(&b.state_diff(&c));
+        assert_eq!(merged.commands().len(), 3);
+        assert!(merged.commands().contains(&KomsiCommand::Speed(45)));
+
+        let mut applied = a.clone();
+        merged
+            .commands()
+            .iter()
+            .for_each(|cmd| applied.apply_command(cmd));
+        assert_eq!(applied, c);
+        assert_eq!(a.state_diff(&a).merge(&a.state_diff(&a)).to_frame(), b"");
+    }
+
+    #[test]
+    fn test_compare_canonical() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.speed = 30;
+        new.ignition = true;
+        new.fuel = 80;
+        new.lights_front_door = true;
+        new.door_clearance = true;
+        new.passengers = 2;
+        assert_eq!(old.compare_canonical(&new, false), b"A1P1H1X2x80y30\n");
+        assert_eq!(old.compare(&new, false, None), b"A1P1H1x80y30X2\n");
+
+        let forced = old.compare_canonical(&old, true);
+        assert!(forced.starts_with(b"A0B0P0C0"));
+        assert_eq!(forced, old.compare_canonical(&old, true));
+    }
+
+    #[test]
+    fn test_compare_to_closest() {
+        let mut current = VehicleState::new();
+        current.ignition = true;
+        current.speed = 50;
+
+        let mut near = VehicleState::new();
+        near.ignition = true;
+        let far = VehicleState::new();
+
+        let (index, buffer) = current
+            .compare_to_closest(&[far.clone(), near, far])
+            .unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(buffer, b"y50\n");
+
+        assert_eq!(current.compare_to_closest(&[]), None);
+    }
+
+    #[test]
+    fn test_compare_with_default_options() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.ignition = true;
+        new.speed = 50;
+
+        let options = CompareOptions::default();
+        assert_eq!(
+            old.compare_with_options(&new, &options, None),
+            old.compare(&new, false, None)
+        );
+
+        let options = CompareOptions {
+            force: true,
+            engine_off_sensors: EngineOffSensors::Zero,
+            ..Default::default()
+        };
+        // the zeroed sensors are moved to the end of the frame
+        let mut commands = old.compare_commands_with_options(&new, &options, None);
+        let mut forced = old.compare_commands(&new, true, None);
+        commands.sort_by_key(|cmd| cmd.kind());
+        forced.sort_by_key(|cmd| cmd.kind());
+        assert_eq!(commands, forced);
+    }
+
+    #[test]
+    fn test_compare_for_version() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.speed = 50;
+        assert_eq!(old.compare_for_version(&new, 1, false, None), b"y50\n");
+        assert!(old.compare_for_version(&new, 0, false, None).is_empty());
+    }
+
+    #[test]
+    fn test_compare_with_deltas() {
+        let deltas = BTreeMap::from([(KomsiCommandKind::Speed, 5), (KomsiCommandKind::Fuel, 2)]);
+        let mut old = VehicleState::new();
+        old.speed = 50;
+        old.fuel = 60;
+
+        let mut new = old.clone();
+        new.speed = 55;
+        new.fuel = 57;
+        new.ignition = true;
+        assert_eq!(old.compare_with_deltas(&new, &deltas), b"A1x57\n");
+
+        new.speed = 44;
+        assert_eq!(old.compare_with_deltas(&new, &deltas), b"A1x57y44\n");
+        assert!(old.compare_with_deltas(&old, &deltas).is_empty());
+    }
 
-        if self.speed != new.speed || force {
-            if let Some(l) = logger {
-                l.log(format!("{}: {} -> {} ", "speed", self.speed, new.speed));
-            }
-            let cmd = KomsiCommand::Speed(new.speed);
-            buffer.extend_from_slice(&cmd.build());
-        }
+    #[test]
+    fn test_speed_limit_signs() {
+        assert_eq!(nearest_speed_limit_sign(55, SPEED_LIMIT_SIGNS), 50);
+        assert_eq!(nearest_speed_limit_sign(57, SPEED_LIMIT_SIGNS), 60);
+        assert_eq!(nearest_speed_limit_sign(90, SPEED_LIMIT_SIGNS), 80);
+        assert_eq!(nearest_speed_limit_sign(200, SPEED_LIMIT_SIGNS), 120);
+        assert_eq!(nearest_speed_limit_sign(55, &[]), 55);
 
-        if self.maxspeed != new.maxspeed || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "maxspeed", self.maxspeed, new.maxspeed
-                ));
-            }
-            let cmd = KomsiCommand::MaxSpeed(new.maxspeed);
-            buffer.extend_from_slice(&cmd.build());
-        }
+        let options = CompareOptions {
+            speed_limit_signs: Some(&[30, 50, 70, 100]),
+            ..Default::default()
+        };
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.maxspeed = 55;
+        assert_eq!(old.compare_with_options(&new, &options, None), b"s50\n");
 
-        if self.battery_light != new.battery_light || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "battery_light", self.battery_light as u8, new.battery_light as u8
-                ));
-            }
-            let cmd = KomsiCommand::BatteryLight(new.battery_light);
-            buffer.extend_from_slice(&cmd.build());
-        }
+        // same sign, nothing to send
+        let mut newer = new.clone();
+        newer.maxspeed = 58;
+        assert!(new.compare_with_options(&newer, &options, None).is_empty());
+    }
 
-        if self.door_clearance != new.door_clearance || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "door_clearance", self.door_clearance as u8, new.door_clearance as u8
-                ));
-            }
-            let cmd = KomsiCommand::DoorClearance(new.door_clearance);
-            buffer.extend_from_slice(&cmd.build());
-        }
+    #[test]
+    fn test_overheat_warning() {
+        let options = CompareOptions {
+            overheat_warning: Some(WATER_OVERHEAT),
+            ..Default::default()
+        };
+        let old = VehicleState {
+            engine: true,
+            water: 90,
+            ..VehicleState::new()
+        };
+        let hot = VehicleState {
+            water: 112,
+            ..old.clone()
+        };
+        assert_eq!(old.compare_with_options(&hot, &options, None), b"F1z112\n");
+        assert_eq!(hot.compare_with_options(&old, &options, None), b"F0z90\n");
 
-        // we send only the total_distance if total_distance_km is changing
-        // we do not want to send to many messages
-        // the meters should be counted and increased in the client
-        if self.total_distance_km != new.total_distance_km || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {} -> {} ",
-                    "odometer", self.total_distance_km as u8, new.total_distance_km as u8
-                ));
-            }
-            let cmd = KomsiCommand::Odometer(new.total_distance);
-            buffer.extend_from_slice(&cmd.build());
-        }
+        // a warning that is already on stays on after cooling down
+        let warned = VehicleState {
+            lights_warning: true,
+            ..hot.clone()
+        };
+        assert_eq!(hot.compare_with_options(&warned, &options, None), b"");
+        let cooled = VehicleState {
+            water: 100,
+            ..warned.clone()
+        };
+        assert_eq!(
+            warned.compare_with_options(&cooled, &options, None),
+            b"z100\n"
+        );
 
-        // we send only the datetime if the minute value is changing
-        // we do not want to send too many messages 
-        // the time should be increased in the client every second
-        if self.datetime.min != new.datetime.min || force {
-            if let Some(l) = logger {
-                l.log(format!(
-                    "{}: {}:{}:{} -> {}:{}:{} ",
-                    "datetime",
-                    self.datetime.hour,
-                    self.datetime.min,
-                    self.datetime.sec,
-                    new.datetime.hour,
-                    new.datetime.min,
-                    new.datetime.sec,
-                ));
-            }
-            let cmd = KomsiCommand::DateTime(new.datetime);
-            buffer.extend_from_slice(&cmd.build());
+        // without the option only the temperature is sent
+        assert_eq!(old.compare(&hot, false, None), b"z112\n");
+    }
+
+    #[test]
+    fn test_indicator() {
+        let variants = [
+            Indicator::Off,
+            Indicator::Left,
+            Indicator::Right,
+            Indicator::Both,
+        ];
+        for (value, indicator) in variants.into_iter().enumerate() {
+            assert_eq!(u8::from(indicator), value as u8);
+            assert_eq!(Indicator::try_from(value as u8), Ok(indicator));
         }
+        assert_eq!(Indicator::try_from(4), Err(KomsiError::InvalidValue));
 
-        // TODO GearSelector, door4 if this will become a KOMSI-protocol entry sometime
+        // the wire format is unchanged
+        let state = VehicleState::new().with_indicator(Indicator::Right.into());
+        assert_eq!(VehicleState::new().compare(&state, false, None), b"D2\n");
+    }
 
-        // add end of line if buffer is not empty
-        if buffer.len() > 0 {
-            let mut b = KomsiCommand::build_eol();
-            buffer.append(&mut b);
+    #[test]
+    fn test_gear_selector() {
+        assert_eq!(GearSelector::Neutral as u8, 0);
+        assert_eq!(GearSelector::Drive as u8, 1);
+        assert_eq!(GearSelector::Reverse as u8, 2);
+        assert_eq!(GearSelector::Park as u8, 3);
+        for value in 0..=3 {
+            assert_eq!(GearSelector::try_from(value).map(u8::from), Ok(value));
         }
+        assert_eq!(GearSelector::try_from(4), Err(KomsiError::InvalidValue));
 
-        buffer
+        let state = VehicleState::new().with_gear_selector(GearSelector::Reverse.into());
+        assert_eq!(VehicleState::new().compare(&state, false, None), b"g2\n");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::{Arc, Mutex};
+    #[test]
+    fn test_validate() {
+        assert_eq!(VehicleState::new().validate(), Ok(()));
+        assert_eq!(VehicleState::from_seed(7).validate(), Ok(()));
 
-    struct TestLogger {
-        logs: Arc<Mutex<Vec<String>>>,
+        let state = VehicleState::new().with_indicator(7);
+        assert_eq!(
+            state.validate(),
+            Err(KomsiError::InvalidField("indicator", 7))
+        );
+        let state = VehicleState::new().with_fuel(101);
+        assert_eq!(state.validate(), Err(KomsiError::InvalidField("fuel", 101)));
+        let state = VehicleState::new().with_stop_request_door(5);
+        assert!(state.validate().is_err());
     }
 
-    impl VehicleLogger for TestLogger {
-        fn log(&self, msg: String) {
-            self.logs.lock().unwrap().push(msg);
-        }
+    #[test]
+    fn test_init_command() {
+        let state = VehicleState::new().with_simulator_type(2).with_speed(50);
+        let init = state.init_command();
+        assert_eq!(init, b"O2\n");
+        assert_eq!(init[0], 79);
+        assert_eq!(init.last(), Some(&10));
+
+        let old = VehicleState::new();
+        assert_eq!(old.compare(&state, false, None), b"y50O2\n");
     }
 
     #[test]
-    fn test_vehicle_state_new() {
-        let state = VehicleState::new();
-        assert_eq!(state.ignition, false);
-        assert_eq!(state.speed, 0);
+    fn test_compare_fourth_door() {
+        let old = VehicleState::new().with_door_clearance(true);
+        let new = old.clone().with_lights_fourth_door(true);
+        assert_eq!(old.compare(&new, false, None), b"Q1\n");
+        assert_eq!(new.compare(&old, false, None), b"Q0\n");
     }
 
     #[test]
-    fn test_compare_no_change() {
+    fn test_compare_gear_selector() {
         let old = VehicleState::new();
-        let new = VehicleState::new();
-        let buffer = old.compare(&new, false, None);
-        assert!(buffer.is_empty());
+        let new = old.clone().with_gear_selector(3);
+        assert_eq!(old.compare(&new, false, None), b"g3\n");
+        assert!(new.compare(&new, true, None).windows(2).any(|w| w == b"g3"));
+        assert_eq!(KomsiCommandKind::GearSelector as u8, b'g');
     }
 
     #[test]
-    fn test_compare_with_changes() {
+    fn test_neutral_lamp() {
+        let options = CompareOptions {
+            neutral_lamp: true,
+            ..Default::default()
+        };
+        let neutral = VehicleState::new().with_gear_selector(GEAR_NEUTRAL);
+        let drive = neutral.clone().with_gear_selector(1);
+        let reverse = neutral.clone().with_gear_selector(2);
+
+        assert_eq!(
+            neutral.compare_with_options(&drive, &options, None),
+            b"g1e0\n"
+        );
+        // a change between two other gears does not touch the lamp
+        assert_eq!(
+            drive.compare_with_options(&reverse, &options, None),
+            b"g2\n"
+        );
+        assert_eq!(
+            reverse.compare_with_options(&neutral, &options, None),
+            b"g0e1\n"
+        );
+        assert_eq!(reverse.compare(&neutral, false, None), b"g0\n");
+    }
+
+    #[test]
+    fn test_brake_lamp() {
+        let options = CompareOptions {
+            brake_lamp: true,
+            ..Default::default()
+        };
         let old = VehicleState::new();
         let mut new = VehicleState::new();
-        new.ignition = true;
-        new.speed = 50;
+        new.fixing_brake = true;
+        assert_eq!(old.compare_with_options(&new, &options, None), b"E1l1\n");
 
-        let buffer = old.compare(&new, false, None);
-        let expected = vec![65, 49, 121, 53, 48, 10];
-        assert_eq!(buffer, expected);
+        // the lamp stays on while the other brake is applied
+        let mut newer = new.clone();
+        newer.lights_stop_brake = true;
+        newer.fixing_brake = false;
+        assert_eq!(new.compare_with_options(&newer, &options, None), b"E0L1\n");
+
+        assert_eq!(newer.compare_with_options(&old, &options, None), b"L0l0\n");
+    }
+
+    #[test]
+    fn test_door_chime() {
+        let options = CompareOptions {
+            door_chime: true,
+            ..Default::default()
+        };
+        let closed = VehicleState::new();
+        let mut open = closed.clone();
+        open.lights_front_door = true;
+        assert_eq!(
+            closed.compare_with_options(&open, &options, None),
+            b"H1h1\n"
+        );
+
+        // once per opening, not while open or when closing
+        assert!(open.compare_with_options(&open, &options, None).is_empty());
+        assert_eq!(open.compare_with_options(&closed, &options, None), b"H0\n");
+
+        let mut both = open.clone();
+        both.lights_second_door = true;
+        assert_eq!(open.compare_with_options(&both, &options, None), b"I1h1\n");
+        assert_eq!(closed.compare(&open, false, None), b"H1\n");
+    }
+
+    #[test]
+    fn test_frame_category() {
+        let old = VehicleState::new();
+        assert_eq!(old.frame_category(&old), None);
+
+        let mut new = old.clone();
+        new.lights_main = true;
+        new.lights_front_door = true;
+        new.lights_second_door = true;
+        assert_eq!(old.frame_category(&new), Some(CommandGroup::Doors));
+
+        // tie between driving and lights
+        new.lights_front_door = false;
+        new.lights_second_door = false;
+        new.speed = 20;
+        assert_eq!(old.frame_category(&new), Some(CommandGroup::Driving));
     }
 
     #[test]
@@ -437,6 +2341,203 @@ mod tests {
         assert_eq!(buffer.last(), Some(&10)); // Should end with Linefeed
     }
 
+    #[test]
+    fn test_critical_changes() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.speed = 50;
+        new.lights_main = true;
+        new.fixing_brake = true;
+        new.lights_front_door = true;
+        new.lights_warning = true;
+
+        let buffer = old.critical_changes(&new);
+        assert_eq!(buffer, b"E1F1H1\n");
+
+        // cosmetic changes only produce no output
+        new = old.clone();
+        new.speed = 50;
+        assert!(old.critical_changes(&new).is_empty());
+    }
+
+    #[test]
+    fn test_safe_state() {
+        let mut state = VehicleState::new();
+        state.engine = true;
+        state.doors = true;
+        state.lights_second_door = true;
+        state.door_clearance = true;
+
+        let safe = state.safe_state();
+        assert!(safe.engine);
+        assert!(safe.lights_warning);
+        assert!(!safe.doors);
+        assert!(!safe.lights_second_door);
+        assert!(!safe.door_clearance);
+
+        let frame = state.safe_state_frame();
+        assert_eq!(frame, safe.compare(&safe, true, None));
+        assert!(frame.windows(2).any(|w| w == b"F1"));
+        assert!(frame.windows(2).any(|w| w == b"B1"));
+    }
+
+    #[test]
+    fn test_sync_doors_flag() {
+        let mut state = VehicleState::new();
+        state.doors = true;
+        state.sync_doors_flag();
+        assert!(!state.doors);
+
+        state.lights_fourth_door = true;
+        state.sync_doors_flag();
+        assert!(state.doors);
+    }
+
+    #[test]
+    fn test_apply_command() {
+        let mut state = VehicleState::new();
+        state.apply_command(&KomsiCommand::Speed(42));
+        state.apply_command(&KomsiCommand::Odometer(12345));
+        state.apply_command(&KomsiCommand::DebugMode(1)); // no field, ignored
+        state.apply_command(&KomsiCommand::RPM(1500));
+        assert_eq!(state.speed, 42);
+        assert_eq!(state.rpm, 1500);
+        assert_eq!(state.total_distance, 12345);
+        assert_eq!(state.total_distance_km, 12);
+
+        state.apply_command(&KomsiCommand::Passengers(23));
+        assert_eq!(state.passengers, 23);
+    }
+
+    #[test]
+    fn test_compare_ac_status() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.ac_status = true;
+        assert_eq!(old.compare(&new, false, None), b"U1\n");
+    }
+
+    #[test]
+    fn test_compare_kneeling() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.kneeling = true;
+        assert_eq!(old.compare(&new, false, None), b"T1\n");
+        assert_eq!(new.compare(&old, false, None), b"T0\n");
+    }
+
+    #[test]
+    fn test_compare_retarder() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.retarder = 3;
+        assert_eq!(old.compare(&new, false, None), b"S3\n");
+
+        // out of range stages are clamped
+        let mut newer = new.clone();
+        newer.retarder = 9;
+        assert_eq!(new.compare(&newer, false, None), b"S5\n");
+        let mut max = new.clone();
+        max.retarder = MAX_RETARDER;
+        assert!(max.compare(&newer, false, None).is_empty());
+
+        let mut state = VehicleState::new();
+        state.apply_command(&KomsiCommand::Retarder(7));
+        assert_eq!(state.retarder, MAX_RETARDER);
+    }
+
+    #[test]
+    fn test_compare_ramp() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.ramp = true;
+        assert_eq!(old.compare(&new, false, None), b"a1\n");
+    }
+
+    #[test]
+    fn test_compare_stop_request_door() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.lights_stop_request = true;
+        new.stop_request_door = 2;
+        assert_eq!(old.compare(&new, false, None), b"K1b2\n");
+    }
+
+    #[test]
+    fn test_compare_passengers() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.passengers = 37;
+        assert_eq!(old.compare(&new, false, None), b"X37\n");
+        assert!(new.compare(&new, false, None).is_empty());
+    }
+
+    #[test]
+    fn test_apply_commands() {
+        let mut state = VehicleState::new();
+        let cmds = [
+            (KomsiCommandKind::Ignition, 1),
+            (KomsiCommandKind::Speed, 50),
+        ];
+        assert_eq!(state.apply_commands(&cmds), Ok(()));
+        assert!(state.ignition);
+        assert_eq!(state.speed, 50);
+
+        let cmds = [
+            (KomsiCommandKind::Speed, 60),
+            (KomsiCommandKind::Fuel, 1000),
+            (KomsiCommandKind::MaxSpeed, 80),
+        ];
+        assert_eq!(
+            state.apply_commands(&cmds),
+            Err(KomsiError::InvalidValueAt(1, KomsiCommandKind::Fuel))
+        );
+        assert_eq!(state.speed, 60);
+        assert_eq!(state.maxspeed, 0);
+    }
+
+    #[test]
+    fn test_apply_buffer() {
+        let mut original = VehicleState::new();
+        original.ignition = true;
+        original.speed = 64;
+        original.lights_third_door = true;
+        original.passengers = 12;
+        original.total_distance = 54321;
+        original.total_distance_km = 54;
+        original.datetime.hour = 7;
+
+        let mut state = VehicleState::new();
+        let buffer = original.compare(&original, true, None);
+        assert_eq!(state.apply_buffer(&buffer), Ok(()));
+        assert_eq!(state, original);
+
+        assert_eq!(state.apply_buffer(b"t900y70\n"), Ok(()));
+        assert_eq!(state.rpm, 900);
+        assert_eq!(state.speed, 70);
+
+        assert_eq!(
+            state.apply_buffer(b"y80x\n"),
+            Err(KomsiError::EmptyValue(KomsiCommandKind::Fuel))
+        );
+        assert_eq!(state.speed, 70);
+    }
+
+    #[test]
+    fn test_roundtrip_check() {
+        let mut state = VehicleState::new();
+        state.ignition = true;
+        state.speed = 85;
+        state.indicator = 2;
+        state.fuel = 42;
+        state.gear_selector = 3;
+        state.total_distance = 123456;
+        state.total_distance_km = 123;
+        state.datetime.hour = 14;
+        assert_eq!(state.roundtrip_check(), Ok(()));
+        assert_eq!(VehicleState::new().roundtrip_check(), Ok(()));
+    }
+
     #[test]
     fn test_multi_value_line_roundtrip() {
         let mut new = VehicleState::new();
@@ -511,4 +2612,166 @@ mod tests {
         assert!(captured_logs.iter().any(|s| s.contains("fuel: 10 -> 20")));
         assert!(captured_logs.iter().any(|s| s.contains("speed: 0 -> 55")));
     }
+
+    #[test]
+    fn test_dry_run() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.speed = 55;
+
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let logger = TestLogger {
+            logs: Arc::clone(&logs),
+        };
+        let options = CompareOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        assert!(
+            old.compare_with_options(&new, &options, Some(&logger))
+                .is_empty()
+        );
+        assert!(
+            old.compare_with_options(&old, &options, Some(&logger))
+                .is_empty()
+        );
+
+        let captured_logs = logs.lock().unwrap();
+        assert_eq!(*captured_logs, ["speed: 0 -> 55 ", "dry run: y55"]);
+    }
+
+    #[test]
+    fn test_compare_with_checksum() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.speed = 55;
+
+        // 121 + 53 + 53 = 227
+        assert_eq!(old.compare_with_checksum(&new, false, None), b"y55j227\n");
+        assert!(old.compare_with_checksum(&old, false, None).is_empty());
+
+        let frame = old.compare_with_checksum(&new, true, None);
+        let plain = old.compare(&new, true, None);
+        let commands = &plain[..plain.len() - 1];
+        assert!(frame.starts_with(commands));
+        assert_eq!(
+            decode_komsi_line(&frame).unwrap().last(),
+            Some(&KomsiCommand::Checksum(line_checksum(commands)))
+        );
+
+        // the checksum composes with the other options
+        let options = CompareOptions {
+            framing: Framing::Checksum,
+            line_ending: LineEnding::CrLf,
+            ..Default::default()
+        };
+        assert_eq!(
+            old.compare_with_options(&new, &options, None),
+            b"y55j227\r\n"
+        );
+    }
+
+    #[test]
+    fn test_reset_commands() {
+        let frame = VehicleState::reset_commands();
+        assert_eq!(frame.last(), Some(&10));
+
+        let commands = decode_komsi_line(&frame).unwrap();
+        let kinds: Vec<KomsiCommandKind> = commands.iter().map(|cmd| cmd.kind()).collect();
+        for cmd in &commands {
+            assert_eq!(cmd.value(), 0, "{cmd:?}");
+        }
+        use KomsiCommandKind as K;
+        let excluded = [
+            K::DateTime,
+            K::ReadyQuery,
+            K::SequenceNumber,
+            K::Timestamp,
+            K::Checksum,
+            K::Crc,
+        ];
+        for kind in KomsiCommandKind::ALL {
+            assert_eq!(kinds.contains(kind), !excluded.contains(kind), "{kind}");
+        }
+        assert_eq!(kinds.len(), KomsiCommandKind::ALL.len() - excluded.len());
+        assert!(commands.contains(&KomsiCommand::Ignition(false)));
+        assert!(commands.contains(&KomsiCommand::Neutral(false)));
+        assert!(commands.contains(&KomsiCommand::BrakeLamp(false)));
+        assert!(commands.contains(&KomsiCommand::DoorChime(false)));
+
+        // independent of the current values
+        let mut state = VehicleState::new();
+        state.speed = 80;
+        state.engine = true;
+        state.apply_buffer(&frame).unwrap();
+        assert_eq!(state.speed, 0);
+        assert!(!state.engine);
+    }
+
+    #[test]
+    fn test_compare_crc8() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.speed = 55;
+        new.fuel = 80;
+
+        let options = CompareOptions {
+            framing: Framing::Crc8,
+            line_ending: LineEnding::CrLf,
+            ..Default::default()
+        };
+        let frame = old.compare_with_options(&new, &options, None);
+        assert!(frame.ends_with(b"\r\n"));
+        assert!(verify_crc8(&frame));
+        assert_eq!(
+            Framing::Crc8.decode_line(&frame),
+            Ok(old.compare_commands(&new, false, None))
+        );
+
+        let mut corrupted = frame.clone();
+        corrupted[1] ^= 0x04;
+        assert!(!verify_crc8(&corrupted));
+        assert_eq!(
+            Framing::Crc8.decode_line(&corrupted),
+            Err(KomsiError::ChecksumMismatch)
+        );
+
+        assert!(old.compare_with_options(&old, &options, None).is_empty());
+
+        // a dry run logs the commands without the CRC
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let logger = TestLogger {
+            logs: Arc::clone(&logs),
+        };
+        let options = CompareOptions {
+            dry_run: true,
+            ..options
+        };
+        assert!(
+            old.compare_with_options(&new, &options, Some(&logger))
+                .is_empty()
+        );
+        assert_eq!(logs.lock().unwrap().last().unwrap(), "dry run: x80y55");
+    }
+
+    #[test]
+    fn test_compare_line_ending() {
+        let old = VehicleState::new();
+        let mut new = VehicleState::new();
+        new.speed = 55;
+
+        let options = CompareOptions {
+            line_ending: LineEnding::CrLf,
+            ..Default::default()
+        };
+        let frame = old.compare_with_options(&new, &options, None);
+        assert_eq!(frame, b"y55\r\n");
+        assert_eq!(frame[frame.len() - 2..], [13, 10]);
+        assert!(old.compare_with_options(&old, &options, None).is_empty());
+        assert_eq!(
+            old.compare_with_options(&new, &CompareOptions::default(), None),
+            old.compare(&new, false, None)
+        );
+    }
 }